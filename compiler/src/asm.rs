@@ -1,35 +1,73 @@
 use std::{collections::HashMap, io::{Read, Write}, num::NonZeroU32, ptr::slice_from_raw_parts};
 
-use crate::bytecode::{self, ByteCode, ClosureArgs};
+use crate::{bytecode::{self, ByteCode, CallArgs}, lout::{self, SectionKind}, err::Result};
 
 pub struct ByteCodeVec{
-    code:Vec<(ByteCode,Option<LabelId>)>
+    code:Vec<ByteCode>,
+    /// Labels and the index of the instruction they point at.
+    labels:Vec<(LabelId,usize)>,
+    /// Stack height after the last instruction, values spread with `VAR_COUNT` aren't counted.
+    /// Slot 0 of every frame holds the function.
+    height:i32,
+    max_height:i32,
 }
 
 impl ByteCodeVec {
     pub fn new() -> Self {
         Self {
-            code:vec![]
+            code:vec![],
+            labels:vec![],
+            height:1,
+            max_height:1,
         }
     }
 
     pub fn add_instr(&mut self,x:ByteCode) {
-        self.code.push((x,None));
+        self.set_height(self.height + x.stack_effect());
+        self.code.push(x);
+    }
+
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    /// For code that is jumped over, or results the stack effect of an instruction can't know.
+    pub fn set_height(&mut self,height:i32) {
+        self.height = height;
+        self.max_height = self.max_height.max(height);
+    }
+
+    pub fn max_stack(&self) -> u32 {
+        self.max_height.max(0) as u32
     }
 
     pub fn add_instr_at(&mut self,x:ByteCode,i:usize) {
-        self.code[i].0 = x;
+        self.code[i] = x;
     }
 
+    /// Points `l` at the next instruction that gets added.
     pub fn add_label(&mut self,l:LabelId) {
-        self.code.last_mut().unwrap().1 = Some(l);
+        self.labels.push((l,self.code.len()));
     }
 
+    /// Points `l` at the instruction after `i`.
     pub fn add_label_at(&mut self,l:LabelId,i:usize) {
-        self.code[i].1 = Some(l);
+        self.labels.push((l,i+1));
+    }
+
+    /// Inserts `x` before the instruction at `i`, labels pointing at `i` point at `x` afterwards.
+    pub fn insert_instr(&mut self,x:ByteCode,i:usize) {
+        self.code.insert(i, x);
+        for (_,idx) in &mut self.labels {
+            if *idx > i {
+                *idx += 1;
+            }
+        }
     }
 
     pub fn append(&mut self,other: &mut Self) {
+        let offset = self.code.len();
+        self.labels.extend(other.labels.drain(..).map(|(l,i)| (l,i+offset)));
         self.code.append(&mut other.code);
     }
 
@@ -37,14 +75,29 @@ impl ByteCodeVec {
         self.code.len()
     }
 
+    pub fn instrs(&self) -> &[ByteCode] {
+        &self.code
+    }
+
+    /// Labels and the index of the instruction they point at.
+    pub fn labels(&self) -> &[(LabelId,usize)] {
+        &self.labels
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
     pub fn print(&self) {
-        let mut i = 0;
-        for (instr,label) in &self.code {
-            println!("{}: {:?} ",i,instr);
-            if let Some(label) = label {
+        let layout = Layout::of(self);
+        for (idx,instr) in self.code.iter().enumerate() {
+            for (label,_) in self.labels.iter().filter(|x| x.1 == idx) {
                 println!("label {:?}:",label.0);
             }
-            i += instr_width(instr);
+            println!("{}: {:?} ",layout.offsets[idx],instr);
+        }
+        for (label,_) in self.labels.iter().filter(|x| x.1 == self.code.len()) {
+            println!("label {:?}:",label.0);
         }
     }
 }
@@ -53,10 +106,41 @@ impl ByteCodeVec {
 #[repr(transparent)]
 pub struct LabelId(NonZeroU32);
 
+impl LabelId {
+    /// The `n`th label, for code that doesn't come from a `CompileCtx`.
+    pub const fn nth(n:u32) -> Self {
+        Self(NonZeroU32::new(n+1).unwrap())
+    }
+
+    pub fn index(self) -> u32 {
+        self.0.get()-1
+    }
+}
+
+/// Where a closure gets an upvalue from when it is created.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpvalDesc {
+    /// A local slot of the enclosing function.
+    Local(u32),
+    /// An upvalue of the enclosing function.
+    Upval(u32),
+}
+
+/// A compiled function, `ByteCode::Closure` refers to it by its index in `CompileCtx`.
+pub struct FuncProto {
+    pub name:Box<str>,
+    pub code:ByteCodeVec,
+    pub arg_count:u16,
+    pub var_args:bool,
+    pub upvals:Vec<UpvalDesc>,
+    pub max_stack:u32,
+}
+
 
 pub struct CompileCtx {
-    name_map:HashMap<Box<str>,u16>,
+    name_map:HashMap<Box<str>,u32>,
     next_label_id:NonZeroU32,
+    protos:Vec<FuncProto>,
 }
 
 impl CompileCtx {
@@ -64,121 +148,249 @@ impl CompileCtx {
         Self{
             name_map:HashMap::new(),
             next_label_id: NonZeroU32::new(1).unwrap(),
+            protos:vec![],
         }
     }
 
+    /// Returns the index `ByteCode::Closure` refers to `proto` by.
+    pub fn add_proto(&mut self,proto:FuncProto) -> u32 {
+        self.protos.push(proto);
+        self.protos.len() as u32 - 1
+    }
+
+    pub fn protos(&self) -> &[FuncProto] {
+        &self.protos
+    }
+
     pub fn new_label(&mut self) -> LabelId {
         let label = LabelId(self.next_label_id);
         self.next_label_id = self.next_label_id.checked_add(1).unwrap();
         label
     }
 
-    pub fn get_idx_of_name(&mut self,name:&str) -> u16 {
+    pub fn get_idx_of_name(&mut self,name:&str) -> u32 {
         if let Some(idx) = self.name_map.get(name) {
             return *idx;
         }
 
         let idx = self.name_map.len();
-        self.name_map.insert(name.into(), idx as u16);
-        idx as u16
+        self.name_map.insert(name.into(), idx as u32);
+        idx as u32
     }
 
-    pub fn encode_name_table(&self,f:&mut std::fs::File) {
-        let low = (self.name_map.len() & 0xFF) as u8;
-        let high = ((self.name_map.len() >> 8) & 0xFF) as u8;
-        f.write_all(&[low,high]).unwrap();
+    pub fn encode_name_table(&self,out:&mut Vec<u8>) {
+        out.extend_from_slice(&(self.name_map.len() as u32).to_le_bytes());
 
-        let mut names = self.name_map.iter().collect::<Vec<(&Box<str>,&u16)>>();
+        let mut names = self.name_map.iter().collect::<Vec<(&Box<str>,&u32)>>();
         names.sort_by_key(|x| x.1);
         for (name,_) in names {
-            f.write_all(name.as_bytes()).unwrap();
-            f.write_all(&[0]).unwrap();
+            out.extend_from_slice(name.as_bytes());
+            out.push(0);
         }
     }
 
-    pub fn write_to_file(&self, bytecode:ByteCodeVec, path:&str) {
-        use std::fs;
+    /// Encodes the name table, the main function made of `bytecode` and the prototype table as a `.lout` file.
+    pub fn encode(&self, bytecode:&ByteCodeVec) -> Result<Vec<u8>> {
+        let mut names = vec![];
+        self.encode_name_table(&mut names);
+
+        let mut main = vec![];
+        encode_proto_head("(main)", 0, false, bytecode.max_stack(), &[], &mut main);
+        encode_code(bytecode, &mut main);
+
+        let mut protos = (self.protos.len() as u32).to_le_bytes().to_vec();
+        for proto in &self.protos {
+            encode_proto_head(&proto.name, proto.arg_count, proto.var_args, proto.max_stack, &proto.upvals, &mut protos);
+            encode_code(&proto.code, &mut protos);
+        }
+
+        lout::encode(&[
+            (SectionKind::Names,names),
+            (SectionKind::Main,main),
+            (SectionKind::Protos,protos),
+        ])
+    }
+
+    pub fn write_to<W:Write>(&self, bytecode:&ByteCodeVec, mut w:W) -> Result<()> {
+        w.write_all(&self.encode(bytecode)?)?;
+        Ok(())
+    }
+
+    pub fn write_to_file(&self, bytecode:&ByteCodeVec, path:impl AsRef<std::path::Path>) -> Result<()> {
+        self.write_to(bytecode, std::fs::File::create(path)?)
+    }
+}
+
+/// Name, arity, `max_stack` and upvalue descriptors of a prototype, the code follows.
+fn encode_proto_head(name:&str,arg_count:u16,var_args:bool,max_stack:u32,upvals:&[UpvalDesc],out:&mut Vec<u8>) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&arg_count.to_le_bytes());
+    out.push(var_args as u8);
+    out.extend_from_slice(&max_stack.to_le_bytes());
 
-        _ = fs::remove_file(path);
-        let mut f = fs::File::create_new(path).unwrap();
-        self.encode_name_table(&mut f);
+    out.extend_from_slice(&(upvals.len() as u32).to_le_bytes());
+    for upval in upvals {
+        let (kind,idx) = match *upval {
+            UpvalDesc::Local(x) => (0,x),
+            UpvalDesc::Upval(x) => (1,x),
+        };
+        out.push(kind);
+        out.extend_from_slice(&idx.to_le_bytes());
+    }
+}
 
-        let mut encoded_bc:Vec<u32> = Vec::with_capacity(bytecode.len()*2);
-        let mut label_map:HashMap<LabelId,usize> = HashMap::with_capacity(bytecode.code.len());
+/// Where every instruction ends up in the encoded code.
+pub struct Layout {
+    /// Word offset of every instruction, followed by the length of the code.
+    pub offsets:Vec<usize>,
+    /// Whether the instruction is a jump that needs the long form.
+    pub long:Vec<bool>,
+    /// The instruction every label points at.
+    labels:HashMap<LabelId,usize>,
+}
 
-        let mut bytecode_len = 0;
-        for (instr,label) in &bytecode.code {
-            bytecode_len += instr_width(&instr);
-            if let Some(label) = label  {
-                label_map.insert(*label, bytecode_len);   
+impl Layout {
+    /// Starts with every jump short and makes the ones that don't reach long,
+    /// until all of them do. Jumps only ever grow, so this ends.
+    pub fn of(bytecode:&ByteCodeVec) -> Self {
+        let mut layout = Layout {
+            offsets:vec![],
+            long:vec![false;bytecode.code.len()],
+            labels:bytecode.labels.iter().copied().collect(),
+        };
+        loop {
+            layout.offsets.clear();
+            let mut len = 0;
+            for (instr,long) in bytecode.code.iter().zip(&layout.long) {
+                layout.offsets.push(len);
+                len += instr_width(instr) + *long as usize;
             }
-        }
+            layout.offsets.push(len);
 
-        for (instr,_) in &bytecode.code {
-            unsafe {
-                use std::mem::transmute;
-                let mut head = [0,0,0,0];
-                head[2] = *(std::ptr::from_ref(instr) as *const u8);
-
-                match *instr {
-                    ByteCode::LoadInt(x) => {
-                        encoded_bc.push(transmute(head));
-                        encoded_bc.push(transmute(x));
-                    }
-        
-                    ByteCode::LoadFloat(x) => {
-                        encoded_bc.push(transmute(head));
-                        encoded_bc.push(transmute(x));
-                    }
-        
-                    ByteCode::Load(x) | ByteCode::Write(x) | ByteCode::LoadStr(x) | 
-                    ByteCode::BindUpval(x) | ByteCode::GetUpval(x) | ByteCode::SetUpval(x) |
-                    ByteCode::GetMethod(x) | ByteCode::NewTable(x) | ByteCode::Call(x) => {
-                        head[0] = (x & 0xFF)as u8;
-                        head[1] = (x >> 8)as u8;
-                        encoded_bc.push(transmute(head));
-                    }
-        
-                    ByteCode::Jump(x) | ByteCode::JumpFalse(x) | ByteCode::JumpTrue(x) => {
-                        let offset = (*label_map.get(&x).unwrap() as i16) - (encoded_bc.len() as i16) - 1;
-                        head[0] = (offset & 0xFF)as u8;
-                        head[1] = (offset >> 8)as u8;
-                        encoded_bc.push(transmute(head));
-                    }
-        
-                    ByteCode::Less(x) | ByteCode::LessEq(x) | ByteCode::Eq(x) => {
-                        head[0] = x as u8;
-                        encoded_bc.push(transmute(head));
-                    }
-        
-                    ByteCode::Closure( ClosureArgs{ label, upval_cap, arg_count }) => {
-                        head[0] = upval_cap;
-                        head[1] = arg_count;
-                        let offset = (*label_map.get(&label).unwrap() as i32) - (encoded_bc.len() as i32) - 2;
-                        encoded_bc.push(transmute(head));
-                        encoded_bc.push(transmute(offset as u32));
-                    }
-        
-                    _ => encoded_bc.push(transmute(head)),
+            let mut changed = false;
+            for i in 0..bytecode.code.len() {
+                if !layout.long[i] && layout.jump_offset(bytecode, i).is_some_and(|x| i16::try_from(x).is_err()) {
+                    layout.long[i] = true;
+                    changed = true;
                 }
             }
+            if !changed {
+                return layout;
+            }
         }
+    }
+
+    /// Offset of the jump at `i` from the instruction after it.
+    fn jump_offset(&self,bytecode:&ByteCodeVec,i:usize) -> Option<i64> {
+        let (ByteCode::Jump(label) | ByteCode::JumpTrue(label) | ByteCode::JumpFalse(label)) = bytecode.code[i] else {
+            return None;
+        };
+        let target = self.labels[&label];
+        Some(self.offsets[target] as i64 - self.offsets[i+1] as i64)
+    }
+}
+
+/// The code length in words, followed by the code.
+fn encode_code(bytecode:&ByteCodeVec,out:&mut Vec<u8>) {
+    let layout = Layout::of(bytecode);
+    let mut encoded_bc:Vec<u32> = Vec::with_capacity(*layout.offsets.last().unwrap());
+
+    for (i,instr) in bytecode.code.iter().enumerate() {
+        let mut head = [0,0,0,0];
+        head[2] = unsafe { *(std::ptr::from_ref(instr) as *const u8) };
+
+        match *instr {
+            ByteCode::LoadInt(x) => {
+                encoded_bc.push(u32::from_ne_bytes(head));
+                encoded_bc.push(x as u32);
+            }
 
-        let slice = unsafe {
-            &*slice_from_raw_parts(encoded_bc.as_ptr() as *const u8, encoded_bc.len()*4)
-        }; 
+            ByteCode::LoadFloat(x) => {
+                encoded_bc.push(u32::from_ne_bytes(head));
+                encoded_bc.push(x.to_bits());
+            }
+
+            ByteCode::Ret(x) | ByteCode::LoadVarArgs(x) => {
+                head[0] = (x & 0xFF)as u8;
+                head[1] = (x >> 8)as u8;
+                encoded_bc.push(u32::from_ne_bytes(head));
+            }
 
-        f.write_all(slice).unwrap();
+            ByteCode::Load(x) | ByteCode::Write(x) | ByteCode::LoadStr(x) |
+            ByteCode::BindUpval(x) | ByteCode::GetUpval(x) | ByteCode::SetUpval(x) |
+            ByteCode::GetMethod(x) | ByteCode::NewTable(x) | ByteCode::GetGlobal(x) |
+            ByteCode::SetGlobal(x) | ByteCode::Next(x) | ByteCode::Close(x) | ByteCode::Closure(x) => {
+                if x > u16::MAX as u32 {
+                    let [b0,b1] = ((x >> 16) as u16).to_le_bytes();
+                    encoded_bc.push(u32::from_ne_bytes([b0,b1,bytecode::EXTEND_ARG,0]));
+                }
+                head[0] = (x & 0xFF)as u8;
+                head[1] = (x >> 8 & 0xFF)as u8;
+                encoded_bc.push(u32::from_ne_bytes(head));
+            }
+
+            ByteCode::Call(CallArgs { arg_count, ret_count }) => {
+                head[0] = (arg_count & 0xFF)as u8;
+                head[1] = (arg_count >> 8)as u8;
+                encoded_bc.push(u32::from_ne_bytes(head));
+                encoded_bc.push(ret_count as u32);
+            }
+
+            ByteCode::Jump(_) | ByteCode::JumpFalse(_) | ByteCode::JumpTrue(_) => {
+                let offset = layout.jump_offset(bytecode, i).unwrap();
+                if layout.long[i] {
+                    head[2] = match instr {
+                        ByteCode::Jump(_) => bytecode::JUMP_LONG,
+                        ByteCode::JumpTrue(_) => bytecode::JUMP_TRUE_LONG,
+                        _ => bytecode::JUMP_FALSE_LONG,
+                    };
+                    encoded_bc.push(u32::from_ne_bytes(head));
+                    encoded_bc.push(offset as i32 as u32);
+                } else {
+                    let offset = offset as i16;
+                    head[0] = (offset & 0xFF)as u8;
+                    head[1] = (offset >> 8)as u8;
+                    encoded_bc.push(u32::from_ne_bytes(head));
+                }
+            }
+
+            ByteCode::Less(x) | ByteCode::LessEq(x) | ByteCode::Eq(x) => {
+                head[0] = x as u8;
+                encoded_bc.push(u32::from_ne_bytes(head));
+            }
+
+            _ => encoded_bc.push(u32::from_ne_bytes(head)),
+        }
     }
+
+    out.extend_from_slice(&(encoded_bc.len() as u32).to_le_bytes());
+    let slice = unsafe {
+        &*slice_from_raw_parts(encoded_bc.as_ptr() as *const u8, encoded_bc.len()*4)
+    }; 
+    out.extend_from_slice(slice);
 }
 
-fn instr_width(instr:&ByteCode) -> usize {
+/// Width in words, long jumps take one more word than this.
+pub fn instr_width(instr:&ByteCode) -> usize {
     match instr {
-        ByteCode::LoadInt(_) | ByteCode::LoadFloat(_) | ByteCode::Closure(_) => 2,
+        ByteCode::LoadInt(_) | ByteCode::LoadFloat(_) | ByteCode::Call(_) => 2,
+        _ if wide_operand(instr).is_some_and(|x| x > u16::MAX as u32) => 2,
         _ => 1
     }
 }
 
+/// The `u32` operand that gets an `EXTEND_ARG` prefix if it doesn't fit into `u16`.
+pub fn wide_operand(instr:&ByteCode) -> Option<u32> {
+    match *instr {
+        ByteCode::Load(x) | ByteCode::Write(x) | ByteCode::LoadStr(x) |
+        ByteCode::BindUpval(x) | ByteCode::GetUpval(x) | ByteCode::SetUpval(x) |
+        ByteCode::GetMethod(x) | ByteCode::NewTable(x) | ByteCode::GetGlobal(x) |
+        ByteCode::SetGlobal(x) | ByteCode::Next(x) | ByteCode::Close(x) | ByteCode::Closure(x) => Some(x),
+        _ => None,
+    }
+}
+
 
 #[test]
 fn layout() {
@@ -186,4 +398,49 @@ fn layout() {
         println!("{:#x}",std::mem::transmute::<ByteCode,u64>(ByteCode::LoadInt(0xaaff)));
         assert_eq!(*(std::ptr::from_ref(&ByteCode::LoadInt(0xffaa)) as *const u8),3);
     }
-}
\ No newline at end of file
+}
+#[test]
+fn long_jump_test() {
+    let mut ctx = CompileCtx::new();
+    let jump_over = |pops:usize,ctx:&mut CompileCtx| {
+        let label = ctx.new_label();
+        let mut code = ByteCodeVec::new();
+        code.add_instr(ByteCode::Jump(label));
+        (0..pops).for_each(|_| code.add_instr(ByteCode::Pop));
+        code.add_label(label);
+        Layout::of(&code).long[0]
+    };
+    assert!(!jump_over(i16::MAX as usize, &mut ctx));
+    assert!(jump_over(i16::MAX as usize + 1, &mut ctx));
+
+    let jump_back = |pops:usize,ctx:&mut CompileCtx| {
+        let label = ctx.new_label();
+        let mut code = ByteCodeVec::new();
+        code.add_label(label);
+        (0..pops).for_each(|_| code.add_instr(ByteCode::Pop));
+        code.add_instr(ByteCode::JumpFalse(label));
+        Layout::of(&code).long[pops]
+    };
+    assert!(!jump_back(i16::MAX as usize, &mut ctx));
+    assert!(jump_back(i16::MAX as usize + 1, &mut ctx));
+
+    // The second jump going long pushes the target of the first one out of reach.
+    let (first,second) = (ctx.new_label(),ctx.new_label());
+    let mut code = ByteCodeVec::new();
+    code.add_instr(ByteCode::JumpTrue(first));
+    (0..i16::MAX as usize - 1).for_each(|_| code.add_instr(ByteCode::Pop));
+    code.add_instr(ByteCode::Jump(second));
+    code.add_label(first);
+    (0..40000).for_each(|_| code.add_instr(ByteCode::Pop));
+    code.add_label(second);
+    code.add_instr(ByteCode::Halt);
+
+    let layout = Layout::of(&code);
+    assert!(layout.long[0] && layout.long[i16::MAX as usize]);
+    let mut out = vec![];
+    encode_code(&code, &mut out);
+    let words:Vec<u32> = out[4..].chunks_exact(4).map(|x| u32::from_ne_bytes(x.try_into().unwrap())).collect();
+    assert_eq!(words.len(),*layout.offsets.last().unwrap());
+    assert_eq!(words[0].to_ne_bytes()[2],bytecode::JUMP_TRUE_LONG);
+    assert_eq!(words[1] as i32,i16::MAX as i32 + 1);
+}
@@ -1,42 +1,51 @@
-use crate::{expr::Expr, tokenizer::Token, err::Result};
+use crate::{expr::{Expr, Op}, tokenizer::Token, err::{Error, ParserErr, Result}, span::{Span, Spanned}};
 
-pub type Block = Vec<AstNode>;
+pub type Block = Vec<Spanned<AstNode>>;
 
 #[derive(Clone)]
 pub enum AstNode {
     Declaration(Declaration),
     Assing(Assing),
-    Call(Expr),
+    OpAssing(OpAssing),
+    Call(Spanned<Expr>),
     If(IfElseStatement),
     For(ForStatement),
     While(WhileStatement),
     Break,
-    Return(Option<Expr>),
+    Return(Vec<Spanned<Expr>>),
     Function(Function),
 }
 
 #[derive(Clone)]
 pub struct Assing {
-    pub lhs:Vec<Expr>,
-    pub rhs:Vec<Expr>
+    pub lhs:Vec<Spanned<Expr>>,
+    pub rhs:Vec<Spanned<Expr>>
+}
+
+/// `x ..= y;`, the target is only evaluated once.
+#[derive(Clone)]
+pub struct OpAssing {
+    pub op:Op,
+    pub lhs:Spanned<Expr>,
+    pub rhs:Spanned<Expr>,
 }
 
 #[derive(Clone)]
 pub struct Declaration {
     pub lhs:Vec<Box<str>>,
-    pub rhs:Vec<Expr>
+    pub rhs:Vec<Spanned<Expr>>
 }
 
 #[derive(Clone)]
 pub struct IfElseStatement {
-    pub cond:Option<Box<Expr>>,
+    pub cond:Option<Box<Spanned<Expr>>>,
     pub block:Block,
     pub next:Option<Box<Self>>
 }
 
 #[derive(Clone)]
 pub struct WhileStatement {
-    pub cond:Box<Expr>,
+    pub cond:Box<Spanned<Expr>>,
     pub block:Block,
 }
 
@@ -45,7 +54,7 @@ pub struct ForStatement {
     pub for_var1:Box<str>,
     pub for_var2:Option<Box<str>>,
     pub iter_type:IterType,
-    pub table:Expr,
+    pub table:Spanned<Expr>,
     pub block:Block,
 }
 
@@ -54,266 +63,354 @@ pub struct Function {
     pub name:Box<str>,
     pub is_local:bool,
     pub args:Vec<Box<str>>,
+    /// Ends with `...`.
+    pub var_args:bool,
     pub block:Block
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum IterType {
+    /// `for i,v in ipairs t {}`
     IPairs,
+    /// `for k,v in kvpairs t {}`
     KVPairs,
+    /// `for i in range n {}`, counts from 0 up to `n`.
     Range,
+    /// `for x in f {}`, calls `f` until it returns nil.
     Generic
 }
 
 
 
+/// Cursor over a slice of tokens.
+/// Syntax errors inside of blocks are collected in `errors` and parsing resumes at the next statement.
+pub struct Parser<'a> {
+    tokens:&'a [Spanned<Token>],
+    pos:usize,
+    pub errors:Vec<Error>,
+}
 
-pub fn parse_block(tokens:&[Token]) -> Result<Block> {
-    if tokens.is_empty() {
-        return Ok(vec![]);
+impl<'a> Parser<'a> {
+    pub fn new(tokens:&'a [Spanned<Token>]) -> Self {
+        Self { tokens, pos:0, errors:vec![] }
     }
-    let mut i = 0;
-    let mut block = vec![];
-    loop {
-        if tokens[i..].is_empty() {
-            break;
-        }
-        let (statement,offset) = parse_statement(&tokens[i..])?;
-        i += offset+1;
-        //println!("{} {}",i,offset);
-        block.push(statement);
+
+    pub fn is_at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    pub fn peek(&self) -> Option<&'a Token> {
+        self.peek_nth(0)
+    }
+
+    pub fn peek_nth(&self,n:usize) -> Option<&'a Token> {
+        self.tokens.get(self.pos+n).map(|x| &x.node)
     }
-    return Ok(block);
-}
 
+    pub fn bump(&mut self) -> Option<&'a Spanned<Token>> {
+        let token = self.tokens.get(self.pos)?;
+        self.pos += 1;
+        Some(token)
+    }
 
-fn parse_statement(tokens:&[Token]) -> Result<(AstNode,usize)> {
-    match tokens[0] {
-        Token::If => {
-            let (s,i) = parse_if_else(tokens,0)?;
-            Ok((AstNode::If(s.unwrap()),i))
+    /// Consumes the next token if it is `token`.
+    pub fn eat(&mut self,token:&Token) -> bool {
+        if self.peek() == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
         }
+    }
 
-        Token::While => {
-            let (s,i) = parse_while(tokens)?;
-            Ok((AstNode::While(s),i))
+    pub fn expect(&mut self,token:&Token,expected:&'static str) -> Result<Span> {
+        if self.peek() != Some(token) {
+            return Err(self.unexpected(expected));
         }
+        Ok(self.bump().unwrap().span)
+    }
 
-        Token::Break => {
-            Ok((AstNode::Break,1))
+    /// Consumes the bracket closing `open`, which was found at `open_span`.
+    pub fn close_bracket(&mut self,open:&Token,open_span:Span,expected:&'static str) -> Result<()> {
+        let close = match open {
+            Token::RoundO => Token::RoundC,
+            Token::CurlyO => Token::CurlyC,
+            _ => Token::SquareC,
+        };
+        if self.eat(&close) {
+            Ok(())
+        } else if self.is_at_end() {
+            Err(Error::Parser(ParserErr::UnmatchedBracket(open.clone()),open_span))
+        } else {
+            Err(self.unexpected(expected))
         }
+    }
 
-        Token::Return => {
-            let end_idx = Token::find_outside_of_brackets(tokens, &Token::Endline).unwrap();
-            Ok((AstNode::Return(Some(Expr::parse(&tokens[1..end_idx])?)),end_idx))
+    pub fn parse_ident(&mut self,expected:&'static str) -> Result<Spanned<Box<str>>> {
+        match self.peek() {
+            Some(Token::Ident(name)) => {
+                let span = self.bump().unwrap().span;
+                Ok(Spanned::new(name.clone(),span))
+            }
+            _ => Err(self.unexpected(expected)),
         }
+    }
+
+    /// Span of the next token.
+    pub fn peek_span(&self) -> Span {
+        self.tokens.get(self.pos).map(|x| x.span).unwrap_or_else(|| self.prev_span())
+    }
+
+    /// Span of the last consumed token.
+    pub fn prev_span(&self) -> Span {
+        self.tokens[..self.pos].last().map(|x| x.span).unwrap_or_default()
+    }
 
-        Token::For => {
-            let (s,i) = parse_for(tokens)?;
-            Ok((AstNode::For(s),i))
+    pub fn unexpected(&self,expected:&'static str) -> Error {
+        Token::unexpected(self.tokens, self.pos, expected)
+    }
+
+    /// Returns the first collected error, if there is one.
+    pub fn into_result<T>(self,x:T) -> Result<T> {
+        match self.errors.into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(x),
         }
+    }
 
-        Token::Ident(_) => {
-            let end_idx = Token::find_outside_of_brackets(tokens, &Token::Endline).unwrap();
-            match Token::find_outside_of_brackets(tokens, &Token::Endline)  {
-                Some(_) => Ok((AstNode::Assing(parse_assing(&tokens[..end_idx])?),end_idx)),
-                None => Ok((AstNode::Call(Expr::parse(&tokens[..end_idx])?),end_idx))
+    /// Parses statements until the end of the tokens, or until a `}` if `in_braces` is set.
+    fn parse_statements(&mut self,in_braces:bool) -> Block {
+        let mut block = vec![];
+        loop {
+            match self.peek() {
+                None => break,
+                Some(Token::CurlyC) if in_braces => break,
+                _ => {}
             }
-        }
-        
-        Token::Local => {
-            if tokens[1] != Token::Function { 
-                let end_idx = Token::find_outside_of_brackets(tokens, &Token::Endline).unwrap();
-                let s = parse_declaration(&tokens[..end_idx])?;
-                Ok((AstNode::Declaration(s),end_idx))
-            } else {
-                let (mut f,i) = parse_function(&tokens[1..])?;
-                f.is_local = true;
-                Ok((AstNode::Function(f),i+1))
+
+            let start = self.pos;
+            let start_span = self.peek_span();
+            match self.parse_statement() {
+                Ok(statement) => block.push(Spanned::new(statement,start_span.to(self.prev_span()))),
+                Err(err) => {
+                    self.errors.push(err);
+                    self.synchronize();
+                    if self.pos == start {
+                        self.bump();
+                    }
+                }
             }
         }
+        block
+    }
 
-        Token::Function => {
-            let (f,i) = parse_function(tokens)?;
-            Ok((AstNode::Function(f),i))
+    /// Skips tokens until the end of the current statement.
+    fn synchronize(&mut self) {
+        while let Some(token) = self.peek() {
+            match token {
+                Token::Endline => {
+                    self.bump();
+                    return;
+                }
+                Token::CurlyC => return,
+                Token::Ident(_) => {}
+                x if x.is_valid_start_of_statement() => return,
+                Token::RoundO|Token::CurlyO|Token::SquareO => {
+                    if let Some(close_idx) = Token::find_matching_bracket(self.tokens, self.pos) {
+                        self.pos = close_idx;
+                    }
+                }
+                _ => {}
+            }
+            self.bump();
         }
+    }
 
-        _ => panic!("invalid token {:?} {:?}",tokens[0],tokens)
+    pub fn parse_braced_block(&mut self) -> Result<Block> {
+        let open_span = self.expect(&Token::CurlyO, "`{`")?;
+        let block = self.parse_statements(true);
+        self.close_bracket(&Token::CurlyO, open_span, "`}`")?;
+        Ok(block)
     }
-}
 
+    fn parse_statement(&mut self) -> Result<AstNode> {
+        match self.peek() {
+            Some(Token::If) => {
+                self.bump();
+                Ok(AstNode::If(self.parse_if_else()?))
+            }
+
+            Some(Token::While) => {
+                self.bump();
+                let cond = self.parse_expr()?;
+                let block = self.parse_braced_block()?;
+                Ok(AstNode::While(WhileStatement{ cond:Box::new(cond), block }))
+            }
+
+            Some(Token::For) => {
+                self.bump();
+                Ok(AstNode::For(self.parse_for()?))
+            }
+
+            Some(Token::Break) => {
+                self.bump();
+                self.expect(&Token::Endline, "`;`")?;
+                Ok(AstNode::Break)
+            }
+
+            Some(Token::Return) => {
+                self.bump();
+                if self.eat(&Token::Endline) {
+                    return Ok(AstNode::Return(vec![]));
+                }
+                let exprs = self.parse_list_of_expr()?;
+                self.expect(&Token::Endline, "`;`")?;
+                Ok(AstNode::Return(exprs))
+            }
+
+            Some(Token::Ident(_)) => {
+                let mut lhs = self.parse_list_of_expr()?;
+                if lhs.len() == 1 && matches!(lhs[0].node,Expr::Call{..}|Expr::MethodCall{..}) && self.eat(&Token::Endline) {
+                    return Ok(AstNode::Call(lhs.pop().unwrap()));
+                }
+
+                if lhs.len() == 1 && self.eat(&Token::ConcatAssing) {
+                    let rhs = self.parse_expr()?;
+                    self.expect(&Token::Endline, "`;`")?;
+                    return Ok(AstNode::OpAssing(OpAssing{ op:Op::Concat, lhs:lhs.pop().unwrap(), rhs }));
+                }
+
+                self.expect(&Token::Assing, "`=`")?;
+                let rhs = self.parse_list_of_expr()?;
+                self.expect(&Token::Endline, "`;`")?;
+                Ok(AstNode::Assing(Assing{ lhs, rhs }))
+            }
+
+            Some(Token::Local) => {
+                self.bump();
+                if self.eat(&Token::Function) {
+                    let mut f = self.parse_function()?;
+                    f.is_local = true;
+                    return Ok(AstNode::Function(f));
+                }
+
+                let lhs = self.parse_list_of_idents()?;
+                self.expect(&Token::Assing, "`=` or `,`")?;
+                let rhs = self.parse_list_of_expr()?;
+                self.expect(&Token::Endline, "`;`")?;
+                Ok(AstNode::Declaration(Declaration{ lhs, rhs }))
+            }
+
+            Some(Token::Function) => {
+                self.bump();
+                Ok(AstNode::Function(self.parse_function()?))
+            }
 
-fn parse_cond(tokens:&[Token]) -> Result<(Expr,usize)> {
-    let mut i = 1;
-    let mut depth = 0;
-    loop {
-        if depth == 0 && tokens[i] == Token::CurlyO {
-            break;
+            _ => Err(self.unexpected("statement"))
         }
-        depth += tokens[i].brack_depth();
-        i += 1;
     }
 
-    let bracket_open_idx = i;
-    let cond = Expr::parse(&tokens[1..bracket_open_idx])?;
-    Ok((cond,bracket_open_idx))
-}
+    /// Parses an `if` or `elif` after the keyword, including the rest of the chain.
+    fn parse_if_else(&mut self) -> Result<IfElseStatement> {
+        let cond = self.parse_expr()?;
+        let block = self.parse_braced_block()?;
 
+        let next = match self.peek() {
+            Some(Token::Elif) => {
+                self.bump();
+                Some(Box::new(self.parse_if_else()?))
+            }
+            Some(Token::Else) => {
+                self.bump();
+                Some(Box::new(IfElseStatement{ cond:None, block:self.parse_braced_block()?, next:None }))
+            }
+            _ => None,
+        };
 
-fn parse_if_else(tokens:&[Token],depth:u32) -> Result<(Option<IfElseStatement>,usize)> {
-    //println!("parsing elif {:?}",tokens);
-    if tokens.is_empty() {
-        //println!("got empty tokens, returning none");
-        return Ok((None,(depth-1) as usize));
-    }
-
-    if !(depth == 0 || tokens[0] == Token::Elif|| tokens[0] == Token::Else) {
-        //println!("got no if/else, returning none");
-        return Ok((None,(depth-1) as usize));
-    }
-
-    match tokens[0] {
-        Token::If | Token::Elif => {
-            //println!("got if/elif");
-            let (cond,bracket_open_idx) = parse_cond(tokens).unwrap();
-            let bracket_close_idx = Token::find_matching_bracket(tokens,bracket_open_idx).unwrap();
-            let block = parse_block(&tokens[bracket_open_idx+1..bracket_close_idx]).unwrap();
-        
-            let (next,offset) = parse_if_else(&tokens[bracket_close_idx+1..],depth+1)?;
-            //println!("offset {}",bracket_close_idx+offset);
-            return Ok((
-                Some(IfElseStatement{
-                    cond:Some(Box::new(cond)),
-                    block,
-                    next:next.map(|x| Box::new(x))
-                }),
-                bracket_close_idx+offset
-            ));
-        }
+        Ok(IfElseStatement{ cond:Some(Box::new(cond)), block, next })
+    }
 
-        Token::Else => {
-            //println!("got else");
-            let bracket_close_idx = Token::find_matching_bracket(tokens,1).unwrap();
-            let block = parse_block(&tokens[2..bracket_close_idx]).unwrap();
-            //println!("offset {}",bracket_close_idx);
-            return Ok((
-                Some(IfElseStatement{
-                    cond: None,
-                    block,
-                    next: None
-                }),
-                bracket_close_idx+depth as usize
-            ));
+    fn parse_for(&mut self) -> Result<ForStatement> {
+        let for_var1 = self.parse_ident("identifier")?.node;
+        let for_var2 = match self.eat(&Token::Comma) {
+            true => Some(self.parse_ident("identifier")?.node),
+            false => None,
+        };
+        self.expect(&Token::In, "`in`")?;
+
+        let iter_type = match self.peek() {
+            Some(Token::IPairs)  => IterType::IPairs,
+            Some(Token::KVPairs) => IterType::KVPairs,
+            Some(Token::Range)   => IterType::Range,
+            _ => IterType::Generic,
+        };
+        if iter_type != IterType::Generic {
+            self.bump();
         }
 
-        _ => panic!()
+        let table = self.parse_expr()?;
+        let block = self.parse_braced_block()?;
+        Ok(ForStatement{ for_var1, for_var2, iter_type, table, block })
     }
-}
-
 
-fn parse_while(tokens:&[Token]) -> Result<(WhileStatement,usize)> {
-    let (cond,bracket_open_idx) = parse_cond(tokens).unwrap();
-    let bracket_close_idx = Token::find_matching_bracket(tokens,bracket_open_idx).unwrap();
-    let block = parse_block(&tokens[bracket_open_idx+1..bracket_close_idx]).unwrap();
-    return Ok((
-        WhileStatement{
-            cond:Box::new(cond),
-            block,
-        },
-        bracket_close_idx
-    ));
-}
+    /// Parses a function after the `function` keyword.
+    fn parse_function(&mut self) -> Result<Function> {
+        let name = self.parse_ident("function name")?.node;
+        let (args,var_args) = self.parse_params()?;
+        let block = self.parse_braced_block()?;
+        Ok(Function{ is_local:false, name, args, var_args, block })
+    }
 
-fn parse_for(tokens:&[Token]) -> Result<(ForStatement,usize)> {
-    let in_idx = Token::find(tokens, &Token::In).unwrap();
-    let for_vars = Token::parse_list_of_idents(&tokens[1..in_idx]);
-    let iter_type = match tokens[in_idx+1] {
-        Token::IPairs  => IterType::IPairs,
-        Token::KVPairs => IterType::KVPairs,
-        Token::Range   => IterType::Range,
-        _ => panic!()
-    };
-
-    let (table,open_bracket_idx) = parse_cond(&tokens[in_idx+1..])?;
-    let open_bracket_idx = open_bracket_idx+in_idx+1;
-    let close_bracket_idx = Token::find_matching_bracket(tokens, open_bracket_idx).unwrap();
-    let block = parse_block(&tokens[open_bracket_idx+1..close_bracket_idx]).unwrap();
-
-    Ok((
-        ForStatement{
-            for_var1:for_vars[0].clone(),
-            for_var2:if tokens.len() != 1 {
-                Some(for_vars[1].clone())
-            } else {
-                None
-            },
-            iter_type,
-            table,
-            block
-        },
-        close_bracket_idx
-    ))
-}
+    /// Parses a parenthesized list of parameter names, which can end with `...`.
+    pub fn parse_params(&mut self) -> Result<(Vec<Box<str>>,bool)> {
+        let open_span = self.expect(&Token::RoundO, "`(`")?;
+        let mut args = vec![];
+        let mut var_args = false;
 
+        while !matches!(self.peek(),Some(Token::RoundC)|None) {
+            if self.eat(&Token::VarArgs) {
+                var_args = true;
+                break;
+            }
+            args.push(self.parse_ident("identifier or `...`")?.node);
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
 
-fn parse_list_of_expr(tokens:&[Token]) -> Result<Vec<Expr>> {
-    let mut out = vec![];
-    parse_list_of_expr_rec(tokens,&mut out)?;
-    Ok(out)
-}
+        self.close_bracket(&Token::RoundO, open_span, if var_args {"`)`"} else {"`,` or `)`"})?;
+        Ok((args,var_args))
+    }
 
-fn parse_list_of_expr_rec(tokens:&[Token], result: &mut Vec<Expr>) -> Result<()> {
-    if tokens.is_empty() {return Ok(());}
-    match Token::find_outside_of_brackets(tokens, &Token::Comma) {
-        Some(i) => {
-            result.push(Expr::parse(&tokens[..i])?);
-            parse_list_of_expr_rec(&tokens[i+1..], result)?;
-            Ok(())
+    fn parse_list_of_idents(&mut self) -> Result<Vec<Box<str>>> {
+        let mut idents = vec![self.parse_ident("identifier")?.node];
+        while self.eat(&Token::Comma) {
+            idents.push(self.parse_ident("identifier")?.node);
         }
+        Ok(idents)
+    }
 
-        None => {
-            result.push(Expr::parse(tokens)?);
-            Ok(())
+    fn parse_list_of_expr(&mut self) -> Result<Vec<Spanned<Expr>>> {
+        let mut exprs = vec![self.parse_expr()?];
+        while self.eat(&Token::Comma) {
+            exprs.push(self.parse_expr()?);
         }
+        Ok(exprs)
     }
 }
 
 
-fn parse_assing(tokens:&[Token]) -> Result<Assing> {
-    let assing_idx = Token::find(tokens, &Token::Assing).unwrap();
-    let lhs = parse_list_of_expr(&tokens[..assing_idx])?;
-    let rhs = parse_list_of_expr(&tokens[assing_idx+1..])?;
-    Ok(Assing{lhs,rhs})
-}
-
-fn parse_declaration(tokens:&[Token]) -> Result<Declaration> {
-    let assing_idx = Token::find(tokens, &Token::Assing).unwrap();
-    let lhs = Token::parse_list_of_idents(&tokens[1..assing_idx]);
-    let rhs = parse_list_of_expr(&tokens[assing_idx+1..])?;
-    Ok(Declaration{lhs,rhs})
+/// Parses a whole file, returning everything that could be parsed together with all syntax errors.
+pub fn parse(tokens:&[Spanned<Token>]) -> (Block,Vec<Error>) {
+    let mut parser = Parser::new(tokens);
+    let block = parser.parse_statements(false);
+    (block,parser.errors)
 }
 
-fn parse_function(tokens:&[Token]) -> Result<(Function,usize)> {
-    let name = match &tokens[1]  {
-        Token::Ident(name) => name.clone(),
-        _ => unreachable!()
-    };
-
-    let open_block_idx = Token::find(tokens, &Token::CurlyO).unwrap();
-    let close_block_idx = Token::find_matching_bracket(tokens, open_block_idx).unwrap();
-
-    let args = Token::parse_list_of_idents(&tokens[3..open_block_idx-1]);
-    let block = parse_block(&tokens[open_block_idx+1..close_block_idx])?;
-
-    return Ok((
-        Function{
-            is_local:false,
-            name,
-            args,
-            block
-        },
-        close_block_idx
-    ));
+pub fn parse_block(tokens:&[Spanned<Token>]) -> Result<Block> {
+    let mut parser = Parser::new(tokens);
+    let block = parser.parse_statements(false);
+    parser.into_result(block)
 }
 
 
@@ -322,9 +419,9 @@ fn if_test() {
     use super::tokenizer;
     let tokens = tokenizer::parse("if x == 2 {}").unwrap();
     let x = parse_block(&tokens).unwrap();
-    match &x[0] {
+    match &x[0].node {
         AstNode::If(IfElseStatement { cond, block, next }) => {
-            cond.as_ref().expect("expected x == 2").display_tree(0);
+            cond.as_ref().expect("expected x == 2").node.display_tree(0);
             assert!(block.is_empty());
             assert!(next.is_none());
         }
@@ -333,9 +430,9 @@ fn if_test() {
 
     let tokens = tokenizer::parse("if x == 2 {} elif y {} else {}").unwrap();
     let x = parse_block(&tokens).unwrap();
-    match &x[0] {
+    match &x[0].node {
         AstNode::If(IfElseStatement { cond, block, next }) => {
-            cond.as_ref().unwrap().display_tree(0);
+            cond.as_ref().unwrap().node.display_tree(0);
             assert!(block.is_empty());
             let next = next.as_ref().expect("expected next");
             assert!(next.next.as_ref().unwrap().cond.is_none());
@@ -363,9 +460,9 @@ fn while_test() {
     use super::tokenizer;
     let tokens = tokenizer::parse("while x == 2 {}").unwrap();
     let x = parse_block(&tokens).unwrap();
-    assert!(match &x[0] {
+    assert!(match &x[0].node {
         AstNode::While(x) => {
-            x.cond.display_tree(0);
+            x.cond.node.display_tree(0);
             assert!(x.block.is_empty());
             true
         }
@@ -378,18 +475,21 @@ fn while_test() {
 #[test]
 fn return_break_test() {
     use super::tokenizer;
-    let tokens = tokenizer::parse("return x+1; break;").unwrap();
+    let tokens = tokenizer::parse("return x+1; break; return; return a,f(b);").unwrap();
     let x = parse_block(&tokens).unwrap();
-    assert!(x.len() == 2);
-    match &x[0] {
+    assert!(x.len() == 4);
+    match &x[0].node {
         AstNode::Return(x) => {
-            x.as_ref().unwrap().display_tree(0);
+            assert_eq!(x.len(),1);
+            x[0].node.display_tree(0);
         }
         _ => panic!() 
     }
+    assert!(matches!(&x[2].node,AstNode::Return(x) if x.is_empty()));
+    assert!(matches!(&x[3].node,AstNode::Return(x) if x.len() == 2));
 
 
-    match x[1] {
+    match x[1].node {
         AstNode::Break => {}
         _ => panic!()
     }
@@ -401,43 +501,68 @@ fn for_test() {
     let tokens = tokenizer::parse("for k,v in kvpairs a[x] {} break;").unwrap();
     let x = parse_block(&tokens).unwrap();
     assert!(x.len() == 2);
-    match &x[0] {
+    match &x[0].node {
         AstNode::For(x) => {
             assert!(x.for_var1 == "k".into());
             assert!(x.for_var2 == Some("v".into()));
             assert!(x.block.is_empty());
-            assert!(match x.table {
-                Expr::Index{ .. } => true,
-                _ => false
-            });
+            assert!(matches!(x.table.node,Expr::Index{ .. }));
             assert!(x.iter_type == IterType::KVPairs);
         }
 
         _ => panic!() 
     }
 
-    match x[1] {
+    match x[1].node {
         AstNode::Break => {}
         _ => panic!()
     }
 }
 
+#[test]
+fn call_test() {
+    use super::tokenizer;
+    let tokens = tokenizer::parse("print(x); obj:update(dt); t.f(1)(2);").unwrap();
+    let x = parse_block(&tokens).unwrap();
+    assert_eq!(x.len(),3);
+    assert!(matches!(&x[0].node,AstNode::Call(Spanned { node:Expr::Call{..}, .. })));
+    assert!(matches!(&x[1].node,AstNode::Call(Spanned { node:Expr::MethodCall{..}, .. })));
+    assert!(matches!(&x[2].node,AstNode::Call(Spanned { node:Expr::Call{..}, .. })));
+
+    let tokens = tokenizer::parse("f(x) + 1;").unwrap();
+    assert!(matches!(parse_block(&tokens),Err(Error::Parser(ParserErr::UnexpectedToken { found:Token::Endline, expected:"`=`" },_))));
+}
+
+#[test]
+fn generic_for_test() {
+    use super::tokenizer;
+    let tokens = tokenizer::parse("for x in iter {}").unwrap();
+    let x = parse_block(&tokens).unwrap();
+    match &x[0].node {
+        AstNode::For(x) => {
+            assert_eq!(x.iter_type,IterType::Generic);
+            assert!(matches!(&x.table.node,Expr::Ident(name) if &**name == "iter"));
+        }
+        _ => panic!()
+    }
+}
+
 #[test]
 fn assing_test() {
     use super::tokenizer;
     let tokens = tokenizer::parse("x,y[i] = f(x),foo; break;").unwrap();
     let x = parse_block(&tokens).unwrap();
     assert!(x.len() == 2);
-    match &x[0] {
+    match &x[0].node {
         AstNode::Assing(x) => {
-            x.lhs.iter().for_each(|x| x.display_tree(0));
-            x.rhs.iter().for_each(|x| x.display_tree(0));
+            x.lhs.iter().for_each(|x| x.node.display_tree(0));
+            x.rhs.iter().for_each(|x| x.node.display_tree(0));
         }
 
         _ => panic!() 
     }
 
-    match x[1] {
+    match x[1].node {
         AstNode::Break => {}
         _ => panic!()
     }
@@ -445,45 +570,134 @@ fn assing_test() {
     let tokens = tokenizer::parse("local x = function(x,y) {return x+y;}; break;").unwrap();
     let x = parse_block(&tokens).unwrap();
     assert_eq!(x.len(),2);
-    match &x[0] {
+    match &x[0].node {
         AstNode::Declaration(x) => {
             x.lhs.iter().for_each(|x| println!("x"));
-            x.rhs.iter().for_each(|x| x.display_tree(0));
+            x.rhs.iter().for_each(|x| x.node.display_tree(0));
         }
 
         _ => panic!() 
     }
 
-    match x[1] {
+    match x[1].node {
         AstNode::Break => {}
         _ => panic!()
     }
 }
 
 
+#[test]
+fn concat_assing_test() {
+    use super::tokenizer;
+    let tokens = tokenizer::parse("t.s ..= \"x\" .. y;").unwrap();
+    let x = parse_block(&tokens).unwrap();
+    match &x[0].node {
+        AstNode::OpAssing(x) => {
+            assert!(matches!((x.op,&x.lhs.node),(Op::Concat,Expr::Index{..})));
+            assert!(matches!(x.rhs.node,Expr::Binary { op:Op::Concat, .. }));
+        }
+        _ => panic!()
+    }
+
+    let tokens = tokenizer::parse("a, b ..= c;").unwrap();
+    assert!(matches!(parse_block(&tokens),Err(Error::Parser(ParserErr::UnexpectedToken{ found:Token::ConcatAssing, .. },_))));
+}
+
 #[test]
 fn function_test() {
     use super::tokenizer;
     let tokens = tokenizer::parse("local function f(a,b,hello) {return {1,2,\"e\"}; } break;").unwrap();
     let x = parse_block(&tokens).unwrap();
     assert!(x.len() == 2);
-    match &x[0] {
+    match &x[0].node {
         AstNode::Function(f) => {
             assert_eq!(f.name,"f".into());
             assert_eq!(f.args[0],"a".into());
             assert_eq!(f.args[1],"b".into());
             assert_eq!(f.args[2],"hello".into());
-            assert!(match f.block[0] {
-               AstNode::Return(_) => true,
-               _ => false 
-            });
+            assert!(!f.var_args);
+            assert!(matches!(f.block[0].node,AstNode::Return(_)));
         }
 
         _ => panic!() 
     }
 
-    match x[1] {
+    match x[1].node {
         AstNode::Break => {}
         _ => panic!()
     }
-}
\ No newline at end of file
+
+    for (src,args,var_args) in [("function f(...) {}",0,true),("function f(a, ...) {}",1,true),("function f() {}",0,false)] {
+        let tokens = tokenizer::parse(src).unwrap();
+        match &parse_block(&tokens).unwrap()[0].node {
+            AstNode::Function(f) => assert_eq!((f.args.len(),f.var_args),(args,var_args)),
+            _ => panic!()
+        }
+    }
+}
+#[test]
+fn span_test() {
+    use super::tokenizer;
+    let src = "local x = 1;\nwhile x {\n  x = x+1;\n}";
+    let tokens = tokenizer::parse(src).unwrap();
+    let x = parse_block(&tokens).unwrap();
+    assert_eq!(&src[x[0].span.start..x[0].span.end],"local x = 1;");
+    assert_eq!((x[1].span.line,x[1].span.col),(2,1));
+    match &x[1].node {
+        AstNode::While(w) => {
+            assert_eq!(&src[w.cond.span.start..w.cond.span.end],"x");
+            assert_eq!(&src[w.block[0].span.start..w.block[0].span.end],"x = x+1;");
+            match &w.block[0].node {
+                AstNode::Assing(a) => {
+                    assert_eq!(&src[a.rhs[0].span.start..a.rhs[0].span.end],"x+1");
+                    assert_eq!((a.rhs[0].span.line,a.rhs[0].span.col),(3,7));
+                }
+                _ => panic!()
+            }
+        }
+        _ => panic!()
+    }
+}
+
+#[test]
+fn recovery_test() {
+    use super::tokenizer;
+    let src = "
+        local x = 1 +;
+        local y = 2;
+        while > y {
+            y = y-;
+        }
+        function f(a {
+            return a;
+        }
+        if x { local z = ; } else { x = y; }
+        }
+        return y;
+    ";
+    let tokens = tokenizer::parse(src).unwrap();
+    let (block,errors) = parse(&tokens);
+
+    let lines:Vec<u32> = errors.iter().map(|x| x.span().unwrap().line).collect();
+    assert_eq!(lines,vec![2,4,7,10,11]);
+
+    assert!(matches!(block[0].node,AstNode::Declaration(_)));
+    match &block[1].node {
+        AstNode::If(x) => {
+            assert!(x.block.is_empty());
+            assert_eq!(x.next.as_ref().unwrap().block.len(),1);
+        }
+        _ => panic!()
+    }
+    assert!(matches!(block[2].node,AstNode::Return(_)));
+    assert_eq!(block.len(),3);
+}
+
+#[test]
+fn unterminated_block_test() {
+    use super::tokenizer;
+    let tokens = tokenizer::parse("while x { x = y;").unwrap();
+    let (block,errors) = parse(&tokens);
+    assert!(block.is_empty());
+    assert!(matches!(errors[..],[Error::Parser(ParserErr::UnmatchedBracket(Token::CurlyO),_)]));
+}
@@ -9,11 +9,12 @@ pub enum ByteCode {
     LoadFalse      = 2,
     LoadInt(i32)   = 3,
     LoadFloat(f32) = 4,
-    LoadStr(u16)   = 6,
+    LoadStr(u32)   = 6,
 
-    Load(u16)  = 7,
-    Write(u16) = 8,
+    Load(u32)  = 7,
+    Write(u32) = 8,
     Pop        = 43,
+    Dup        = 53,
 
     Add    =  9,
     Sub    = 10,
@@ -42,20 +43,37 @@ pub enum ByteCode {
     BoolNot  = 38,
     Len      = 39,
 
-    NewTable(u16) = 42,
+    NewTable(u32) = 42,
     Get = 44,
     Set = 46,
     SetPop = 47,
     Push = 48,
-    GetMethod(u16) = 49,
+    /// Pops a count and that many values and appends the values to the table below them.
+    PushAll = 55,
+    /// Replaces the table on top of the stack with the method of that name from its metatable.
+    GetMethod(u32) = 49,
+    /// Advances the iterator in the locals `slot` (table), `slot+1` (key) and `slot+2` (value),
+    /// and pushes whether there was another pair.
+    Next(u32) = 52,
 
-    Closure(ClosureArgs) = 17,
-    Call(u16) = 18,
-    Ret = 19,
+    /// Creates a closure of the prototype with the index, capturing its upvalues as the prototype describes.
+    Closure(u32) = 17,
+    Call(CallArgs) = 18,
+    /// Returns the top `n` values, or as many as the count on top of the stack says if `n` is `VAR_COUNT`.
+    Ret(u16) = 19,
 
-    BindUpval(u16) = 20,
-    GetUpval(u16)  = 21,
-    SetUpval(u16)  = 22,
+    BindUpval(u32) = 20,
+    GetUpval(u32)  = 21,
+    SetUpval(u32)  = 22,
+    /// Moves the captured locals in `slot` and above off the stack into their own cells.
+    Close(u32) = 58,
+
+    /// Pushes the first `n` extra arguments, padded with nil,
+    /// or all of them followed by their count if `n` is `VAR_COUNT`.
+    LoadVarArgs(u16) = 54,
+
+    GetGlobal(u32) = 50,
+    SetGlobal(u32) = 51,
 
     Jump(LabelId)      = 23,
     JumpTrue(LabelId)  = 24,
@@ -64,10 +82,125 @@ pub enum ByteCode {
     Halt = 30,
 }
 
-#[repr(packed)]
+/// `Ret` count and `CallArgs::ret_count` for a variable number of values.
+/// After a call with it the results are followed by their count.
+pub const VAR_COUNT:u16 = u16::MAX;
+pub const VAR_RET_COUNT:u16 = u16::MAX;
+
+/// Opcodes of `Jump`, `JumpTrue` and `JumpFalse` with an `i32` offset in the word after them.
+/// The encoder picks them for offsets that don't fit into the `i16` payload.
+pub const JUMP_LONG:u8       = 59;
+pub const JUMP_TRUE_LONG:u8  = 60;
+pub const JUMP_FALSE_LONG:u8 = 61;
+
+/// Prefix for operands that don't fit into `u16`, its payload is the upper half of the next instruction's operand.
+pub const EXTEND_ARG:u8 = 62;
+
+/// The callee's results replace the return slot pushed before the arguments,
+/// padded with nil or truncated to `ret_count`.
+/// If `arg_count` is `VAR_COUNT` the argument count is right below the function.
+/// `ret_count` is encoded in the word after the call.
+#[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub struct ClosureArgs{
-    pub label:LabelId,
-    pub upval_cap:u8,
-    pub arg_count:u8,
-}
\ No newline at end of file
+pub struct CallArgs{
+    pub arg_count:u16,
+    pub ret_count:u16,
+}
+
+impl ByteCode {
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            ByteCode::LoadNil => "load_nil",
+            ByteCode::LoadTrue => "load_true",
+            ByteCode::LoadFalse => "load_false",
+            ByteCode::LoadInt(_) => "load_int",
+            ByteCode::LoadFloat(_) => "load_float",
+            ByteCode::LoadStr(_) => "load_str",
+            ByteCode::Load(_) => "load",
+            ByteCode::Write(_) => "write",
+            ByteCode::Pop => "pop",
+            ByteCode::Dup => "dup",
+            ByteCode::Add => "add",
+            ByteCode::Sub => "sub",
+            ByteCode::Mul => "mul",
+            ByteCode::Div => "div",
+            ByteCode::IDiv => "idiv",
+            ByteCode::Pow => "pow",
+            ByteCode::Mod => "mod",
+            ByteCode::Concat => "concat",
+            ByteCode::And => "and",
+            ByteCode::Or => "or",
+            ByteCode::Xor => "xor",
+            ByteCode::Shl => "shl",
+            ByteCode::Shr => "shr",
+            ByteCode::BoolAnd => "bool_and",
+            ByteCode::BoolOr => "bool_or",
+            ByteCode::Less(_) => "less",
+            ByteCode::LessEq(_) => "less_eq",
+            ByteCode::Eq(_) => "eq",
+            ByteCode::Neg => "neg",
+            ByteCode::Not => "not",
+            ByteCode::BoolNot => "bool_not",
+            ByteCode::Len => "len",
+            ByteCode::NewTable(_) => "new_table",
+            ByteCode::Get => "get",
+            ByteCode::Set => "set",
+            ByteCode::SetPop => "set_pop",
+            ByteCode::Push => "push",
+            ByteCode::PushAll => "push_all",
+            ByteCode::GetMethod(_) => "get_method",
+            ByteCode::Next(_) => "next",
+            ByteCode::Closure(_) => "closure",
+            ByteCode::Call(_) => "call",
+            ByteCode::Ret(_) => "ret",
+            ByteCode::BindUpval(_) => "bind_upval",
+            ByteCode::GetUpval(_) => "get_upval",
+            ByteCode::SetUpval(_) => "set_upval",
+            ByteCode::Close(_) => "close",
+            ByteCode::LoadVarArgs(_) => "load_var_args",
+            ByteCode::GetGlobal(_) => "get_global",
+            ByteCode::SetGlobal(_) => "set_global",
+            ByteCode::Jump(_) => "jump",
+            ByteCode::JumpTrue(_) => "jump_true",
+            ByteCode::JumpFalse(_) => "jump_false",
+            ByteCode::Halt => "halt",
+        }
+    }
+
+    /// How much the instruction changes the height of the stack.
+    /// Values spread with `VAR_COUNT` aren't counted, so calls that take them are off.
+    pub fn stack_effect(&self) -> i32 {
+        match *self {
+            ByteCode::LoadNil | ByteCode::LoadTrue | ByteCode::LoadFalse |
+            ByteCode::LoadInt(_) | ByteCode::LoadFloat(_) | ByteCode::LoadStr(_) |
+            ByteCode::Load(_) | ByteCode::Dup | ByteCode::GetUpval(_) | ByteCode::GetGlobal(_) |
+            ByteCode::NewTable(_) | ByteCode::Next(_) | ByteCode::Closure(_) => 1,
+
+            ByteCode::LoadVarArgs(VAR_COUNT) => 1,
+            ByteCode::LoadVarArgs(n) => n as i32,
+
+            ByteCode::Write(_) | ByteCode::Pop | ByteCode::BindUpval(_) | ByteCode::SetUpval(_) |
+            ByteCode::SetGlobal(_) | ByteCode::JumpTrue(_) | ByteCode::JumpFalse(_) => -1,
+
+            ByteCode::Add | ByteCode::Sub | ByteCode::Mul | ByteCode::Div | ByteCode::IDiv |
+            ByteCode::Pow | ByteCode::Mod | ByteCode::Concat | ByteCode::And | ByteCode::Or |
+            ByteCode::Xor | ByteCode::Shl | ByteCode::Shr | ByteCode::BoolAnd | ByteCode::BoolOr |
+            ByteCode::Less(_) | ByteCode::LessEq(_) | ByteCode::Eq(_) |
+            ByteCode::Get | ByteCode::Push | ByteCode::PushAll => -1,
+
+            ByteCode::Set => -2,
+            ByteCode::SetPop => -3,
+
+            ByteCode::Neg | ByteCode::Not | ByteCode::BoolNot | ByteCode::Len | ByteCode::GetMethod(_) |
+            ByteCode::Close(_) | ByteCode::Jump(_) | ByteCode::Halt => 0,
+
+            ByteCode::Call(CallArgs{ arg_count, ret_count }) => {
+                let args = if arg_count == VAR_COUNT {1} else {arg_count as i32};
+                let rets = if ret_count == VAR_RET_COUNT {1} else {ret_count as i32};
+                rets - args - 2
+            }
+            ByteCode::Ret(VAR_COUNT) => -1,
+            ByteCode::Ret(n) => -(n as i32),
+        }
+    }
+}
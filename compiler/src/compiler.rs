@@ -1,40 +1,51 @@
 use std::{collections::HashMap, num::NonZeroU32, sync::Mutex};
 
-use crate::{asm::{ByteCodeVec, CompileCtx, LabelId}, ast_gen::{Assing, AstNode, Block, Declaration, ForStatement, Function, IfElseStatement, WhileStatement}, bytecode::{ByteCode, ClosureArgs}, expr::{self, Expr, InlineFunction, Op, TableLiteral, TableLiteralIdx, UnaryOp}};
+use crate::{asm::{ByteCodeVec, CompileCtx, FuncProto, LabelId, UpvalDesc}, ast_gen::{Assing, AstNode, Block, Declaration, OpAssing, ForStatement, Function, IfElseStatement, IterType, WhileStatement}, bytecode::{ByteCode, CallArgs, VAR_COUNT, VAR_RET_COUNT}, err::{CompilerErr, Error, Result}, expr::{self, Expr, InlineFunction, Op, TableLiteral, TableLiteralIdx, UnaryOp}, span::{Span, Spanned}};
 
 
-pub struct FuncCtx<'a> {
+pub struct FuncCtx {
     pub prev:Option<*mut Self>,
-    pub sub_funcs:Vec<Self>,
 
     scope_depth:u32,
     args:Vec<Box<str>>,
-    locals:Vec<(Box<str>,u32)>,
-    upvals:Vec<Box<str>>,
+    var_args:bool,
+    locals:Vec<Local>,
+    upvals:Vec<(Box<str>,UpvalDesc)>,
+    /// Some local or argument is captured, so it has to be closed before returning.
+    has_captures:bool,
+}
+
+struct Local {
+    name:Box<str>,
+    depth:u32,
+    /// Captured by a closure, so it has to be closed before it is popped.
+    captured:bool,
+}
 
-    sub_func_labels:Vec<LabelId>,
-    sub_func_bytecode:Vec<ByteCodeVec>,
+/// Where `break` jumps to, and how many locals are alive there.
+#[derive(Clone, Copy)]
+struct BreakTarget {
+    label:LabelId,
+    local_count:usize,
 }
 
 enum VarKind {
-    Local(u16),
+    Local(u32),
     Global(Box<str>),
-    Upval(u16),
+    Upval(u32),
 }
 
-impl<'a> FuncCtx<'a> {
+impl FuncCtx {
     pub fn new(args:&[Box<str>]) -> Self {
         let mut ctx = Self {
             prev:None,
-            sub_funcs:vec![],
 
             args:vec![],
+            var_args:false,
             scope_depth:0,
             locals: vec![],
             upvals:vec![], 
-
-            sub_func_labels:vec![],
-            sub_func_bytecode:vec![],
+            has_captures:false,
         };
 
         for arg in args {
@@ -56,13 +67,30 @@ impl<'a> FuncCtx<'a> {
         }
     }
 
-    fn local_count(&mut self) -> usize {
+    fn local_count(&self) -> usize {
         self.locals.len() + self.args.len()
     }
 
-    fn add_local(&mut self,name:&str) {
+    /// Returns the slot of the new local.
+    fn add_local(&mut self,name:&str) -> u32 {
         let id = self.local_count();
-        self.locals.push((name.into(),self.scope_depth));
+        self.locals.push(Local{ name:name.into(), depth:self.scope_depth, captured:false });
+        id as u32 + 1
+    }
+
+    fn capture_local(&mut self,id:u32) {
+        self.has_captures = true;
+        if let Some(local) = (id as usize).checked_sub(self.args.len()).and_then(|i| self.locals.get_mut(i)) {
+            local.captured = true;
+        }
+    }
+
+    /// Emits a `Close` if one of the locals after the first `local_count` ones is captured.
+    fn close_above(&self,local_count:usize,bytecode:&mut ByteCodeVec) {
+        let first = local_count.saturating_sub(self.args.len());
+        if self.locals.iter().skip(first).any(|x| x.captured) {
+            bytecode.add_instr(ByteCode::Close(local_count as u32 + 1));
+        }
     }
 
     fn kind_of_ident(&mut self,name:&str) -> VarKind {
@@ -73,144 +101,190 @@ impl<'a> FuncCtx<'a> {
         //println!("");
 
         if let Some((id,_)) = self.locals.iter().enumerate().rev()
-        .find(|(i,x)| *x.0 == *name) {
-            return VarKind::Local(id as u16 + self.args.len() as u16);
+        .find(|(i,x)| *x.name == *name) {
+            return VarKind::Local((id + self.args.len()) as u32);
         }
 
         if let Some((id,_)) = self.args.iter().enumerate().rev()
         .find(|(i,x)| ***x == *name) {
-            return VarKind::Local(id as u16);
+            return VarKind::Local(id as u32);
         }
 
         if let Some(id) = self.upvals.iter().enumerate()
-        .find(|(i,x)| ***x == *name)
-        .map(|(i,_)| i as u16) {
+        .find(|(i,x)| *x.0 == *name)
+        .map(|(i,_)| i as u32) {
             return VarKind::Upval(id);
         }
 
+        // Intermediate functions capture the name too, so it can be passed down.
         if let Some(prev) = self.get_prev_mut() {
-            match prev.kind_of_ident(name) {
-                VarKind::Local(_) => {
-                    let id = self.upvals.len();
-                    self.upvals.push(name.into());
-                    return VarKind::Upval(id as u16);
-                },
-                _ => {}
-            }
+            let desc = match prev.kind_of_ident(name) {
+                VarKind::Local(id) => {
+                    prev.capture_local(id);
+                    UpvalDesc::Local(id+1)
+                }
+                VarKind::Upval(id) => UpvalDesc::Upval(id),
+                VarKind::Global(_) => return VarKind::Global(name.into()),
+            };
+            let id = self.upvals.len();
+            self.upvals.push((name.into(),desc));
+            return VarKind::Upval(id as u32);
         }
 
-        return VarKind::Global(name.into());
+        VarKind::Global(name.into())
     }
 
     fn up_scope(&mut self) {
         self.scope_depth += 1;
     }
 
-    fn down_scope(&mut self) {
-        loop {
-            if self.locals.last().unwrap().1 != self.scope_depth {
-                break;
-            }
+    /// Drops the locals of the current scope, closes the captured ones and pops them off the stack.
+    fn down_scope(&mut self,bytecode:&mut ByteCodeVec) {
+        let scope_len = self.locals.iter().rev().take_while(|x| x.depth == self.scope_depth).count();
+        self.close_above(self.local_count()-scope_len, bytecode);
+        for _ in 0..scope_len {
             _ = self.locals.pop();
+            bytecode.add_instr(ByteCode::Pop);
         }
         self.scope_depth -= 1;
     }
 
+    /// Compiles the body of a function, the functions defined inside it are added to `comp_ctx`.
     pub fn compile(
         &mut self,
-        block:&[AstNode],
+        block:&[Spanned<AstNode>],
         comp_ctx:&mut CompileCtx,
         bytecode:&mut ByteCodeVec,
         encode_at_end:Option<ByteCode>,
-        break_label:Option<LabelId>
-    ) {
-
-        let mut sub_func_blocks = vec![];
-        for node in block { if let AstNode::Function(func) = node {
-            self.add_local(&func.name.clone());
-            sub_func_blocks.push(&func.block);
-
-            let mut func = Self::new(&func.args);
-            func.prev = Some(self);
-            self.sub_funcs.push(func);
-        }}
+    ) -> Result<()> {
+        let start = bytecode.len();
+        bytecode.set_height(self.local_count() as i32 + 1);
+        self.compile_block(block, comp_ctx, bytecode, None)?;
 
-        for (i,sub_func) in self.sub_funcs.iter_mut().enumerate() {
-            let mut func_bytecode = ByteCodeVec::new();
-            sub_func.compile(sub_func_blocks[i], comp_ctx, &mut func_bytecode, Some(ByteCode::Ret), None);
-            self.sub_func_bytecode.push(func_bytecode);
-
-            let label = comp_ctx.new_label();
-            self.sub_func_labels.push(label);
-            bytecode.add_instr(ByteCode::Closure(ClosureArgs{
-                label,
-                upval_cap: sub_func.upvals.len() as u8,
-                arg_count: sub_func.args.len() as u8 
-            }));
+        if let Some(instr) = encode_at_end {
+            bytecode.add_instr(instr);
         }
 
-        for (sub_func_idx,sub_func) in self.sub_funcs.iter_mut().enumerate() {
-            if !sub_func.upvals.is_empty() {
-                bytecode.add_instr(ByteCode::Load((sub_func_idx+1) as u16));
-
-                for (upval_idx,upval) in sub_func.upvals.iter().enumerate() {
-                    let id = self.locals.iter().enumerate().rev()
-                        .find(|(i,x)| *x.0 == **upval)
-                        .map(|(i,_)| i as u16)
-                        .unwrap();
-
-                        bytecode.add_instr(ByteCode::Load(id+1));
-                        bytecode.add_instr(ByteCode::BindUpval(upval_idx as u16));
+        // Captures are only known once the whole body is compiled.
+        if self.has_captures {
+            let mut i = start;
+            while i < bytecode.len() {
+                if let ByteCode::Ret(_) = bytecode.instrs()[i] {
+                    bytecode.insert_instr(ByteCode::Close(1), i);
+                    i += 1;
                 }
-
-                bytecode.add_instr(ByteCode::Write((sub_func_idx+1) as u16));
+                i += 1;
             }
         }
+        Ok(())
+    }
 
+    /// Compiles a function into a new prototype and pushes a closure of it.
+    #[allow(clippy::too_many_arguments)]
+    fn compile_closure(
+        &mut self,
+        name:&str,
+        args:&[Box<str>],
+        var_args:bool,
+        block:&[Spanned<AstNode>],
+        comp_ctx:&mut CompileCtx,
+        bytecode:&mut ByteCodeVec,
+        span:Span,
+    ) -> Result<()> {
+        let arg_count = u16::try_from(args.len())
+            .map_err(|_| Error::Compiler(CompilerErr::TooManyParams{ max:u16::MAX as usize },span))?;
+        let mut sub_func = Self::new(args);
+        sub_func.var_args = var_args;
+        sub_func.prev = Some(self);
+        let mut func_bytecode = ByteCodeVec::new();
+        sub_func.compile(block, comp_ctx, &mut func_bytecode, Some(ByteCode::Ret(0)))?;
+
+        let proto = comp_ctx.add_proto(FuncProto {
+            name:name.into(),
+            max_stack:func_bytecode.max_stack(),
+            code:func_bytecode,
+            arg_count,
+            var_args,
+            upvals:sub_func.upvals.into_iter().map(|(_,desc)| desc).collect(),
+        });
+        bytecode.add_instr(ByteCode::Closure(proto));
+        Ok(())
+    }
 
-        for node in block { match node {
+    fn compile_block(
+        &mut self,
+        block:&[Spanned<AstNode>],
+        comp_ctx:&mut CompileCtx,
+        bytecode:&mut ByteCodeVec,
+        break_target:Option<BreakTarget>
+    ) -> Result<()> {
+
+        // Every function gets its slot first, so they can capture each other.
+        // The closures are created in place, so they see the locals declared before them.
+        let mut func_slots = vec![];
+        for node in block { if let AstNode::Function(func) = &node.node {
+            bytecode.add_instr(ByteCode::LoadNil);
+            func_slots.push(self.add_local(&func.name));
+        }}
+        let mut func_slots = func_slots.into_iter();
+
+        for node in block { match &node.node {
             AstNode::Declaration(Declaration { lhs, rhs }) => {
-                lhs.iter().for_each(|x| self.add_local(x));
-                rhs.iter().for_each(|x| x.compile(self, comp_ctx,bytecode));
+                self.compile_expr_list(rhs, lhs.len(), comp_ctx, bytecode)?;
+                lhs.iter().for_each(|x| _ = self.add_local(x));
             }
 
             AstNode::Assing(Assing { lhs, rhs }) => {
-                for (expr,lhs) in rhs.iter().zip(lhs) {
-                    match lhs {
-                        Expr::Index { table, idx } => {
-                            table.compile(self, comp_ctx, bytecode);
-                            idx.compile(self, comp_ctx, bytecode);
-                        },
-                        _ => {},
-                    }
-                    expr.compile(self,comp_ctx,bytecode);
-                }
+                // The values are assigned from the top of the stack down, table fields copy theirs.
+                self.compile_expr_list(rhs, lhs.len(), comp_ctx, bytecode)?;
+                let first_value = self.local_count() as u32 + 1;
 
-                for lhs in lhs.iter().rev() {
-                    match lhs {
-                        Expr::Ident(name) => {
-                            match self.kind_of_ident(name) {
-                                VarKind::Local(id) => bytecode.add_instr(ByteCode::Write(id+1)),
-                                VarKind::Global(name) => panic!("globals unimplimented: {}",name),
-                                VarKind::Upval(id) => bytecode.add_instr(ByteCode::SetUpval(id)),
-                            }
-                        }
-                        
-                        Expr::Index {..} => {
+                for (i,lhs) in lhs.iter().enumerate().rev() {
+                    match &lhs.node {
+                        Expr::Ident(name) => write_ident(name, self, comp_ctx, bytecode),
+
+                        Expr::Index { table, idx } => {
+                            table.compile(self, comp_ctx, bytecode)?;
+                            idx.compile(self, comp_ctx, bytecode)?;
+                            bytecode.add_instr(ByteCode::Load(first_value + i as u32));
                             bytecode.add_instr(ByteCode::SetPop);
+                            bytecode.add_instr(ByteCode::Pop);
                         },
 
-                        _ => unreachable!(),
+                        _ => return Err(Error::Compiler(CompilerErr::InvalidAssingTarget,lhs.span)),
                     }
                 }
             }
 
-            AstNode::Return(expr) => {
-                if let Some(expr) = expr {
-                    expr.compile(self, comp_ctx, bytecode);
-                    bytecode.add_instr(ByteCode::Write(0));
+            AstNode::OpAssing(OpAssing { op, lhs, rhs }) => match &lhs.node {
+                Expr::Ident(name) => {
+                    lhs.compile(self, comp_ctx, bytecode)?;
+                    rhs.compile(self, comp_ctx, bytecode)?;
+                    bytecode.add_instr(op_instr(*op));
+                    write_ident(name, self, comp_ctx, bytecode);
+                }
+
+                // The table and key are copied for the read, the originals are left for the write.
+                Expr::Index { table, idx } => {
+                    table.compile(self, comp_ctx, bytecode)?;
+                    idx.compile(self, comp_ctx, bytecode)?;
+                    let key = bytecode.height() as u32 - 1;
+                    bytecode.add_instr(ByteCode::Load(key-1));
+                    bytecode.add_instr(ByteCode::Load(key));
+                    bytecode.add_instr(ByteCode::Get);
+                    rhs.compile(self, comp_ctx, bytecode)?;
+                    bytecode.add_instr(op_instr(*op));
+                    bytecode.add_instr(ByteCode::SetPop);
                 }
-                bytecode.add_instr(ByteCode::Ret);
+
+                _ => return Err(Error::Compiler(CompilerErr::InvalidAssingTarget,lhs.span)),
+            },
+
+            AstNode::Return(exprs) => {
+                let height = bytecode.height();
+                let count = compile_spread(exprs, 0, self, comp_ctx, bytecode, node.span)?;
+                bytecode.add_instr(ByteCode::Ret(count));
+                bytecode.set_height(height);
             }
 
             AstNode::If(x) => {
@@ -219,26 +293,26 @@ impl<'a> FuncCtx<'a> {
 
                 loop {
                     if let Some(cond) = current.cond.clone() {
-                        cond.compile(self, comp_ctx, bytecode);
+                        cond.compile(self, comp_ctx, bytecode)?;
                         let else_label = comp_ctx.new_label();
                         bytecode.add_instr(ByteCode::JumpFalse(else_label));
 
                         self.up_scope();
-                        self.compile(&current.block, comp_ctx, bytecode, None, break_label);
-                        self.down_scope();
+                        self.compile_block(&current.block, comp_ctx, bytecode, break_target)?;
+                        self.down_scope(bytecode);
                         bytecode.add_instr(ByteCode::Jump(end_label));
                         bytecode.add_label(else_label);
                     } else {
                         self.up_scope();
-                        self.compile(&current.block, comp_ctx, bytecode, None, break_label);
-                        self.down_scope();
+                        self.compile_block(&current.block, comp_ctx, bytecode, break_target)?;
+                        self.down_scope(bytecode);
                     }
 
                     if current.next.is_none() {
                         break;
                     }
 
-                    current = &current.next.as_ref().unwrap();
+                    current = current.next.as_ref().unwrap();
                 }
 
                 bytecode.add_label(end_label);
@@ -249,83 +323,288 @@ impl<'a> FuncCtx<'a> {
                 let start_label = comp_ctx.new_label();
                 bytecode.add_label(start_label);
 
-                cond.compile(self, comp_ctx, bytecode);
+                cond.compile(self, comp_ctx, bytecode)?;
                 bytecode.add_instr(ByteCode::JumpFalse(end_label));
 
+                let break_target = BreakTarget{ label:end_label, local_count:self.local_count() };
                 self.up_scope();
-                self.compile(block, comp_ctx, bytecode, None, Some(end_label));
-                self.down_scope();
+                self.compile_block(block, comp_ctx, bytecode, Some(break_target))?;
+                self.down_scope(bytecode);
 
                 bytecode.add_instr(ByteCode::Jump(start_label));
                 bytecode.add_label(end_label);
             }
 
-            AstNode::Break => bytecode.add_instr(ByteCode::Jump(break_label.unwrap())),
+            AstNode::Break => match break_target {
+                Some(BreakTarget { label, local_count }) => {
+                    let height = bytecode.height();
+                    self.close_above(local_count, bytecode);
+                    for _ in local_count..self.local_count() {
+                        bytecode.add_instr(ByteCode::Pop);
+                    }
+                    bytecode.add_instr(ByteCode::Jump(label));
+                    bytecode.set_height(height);
+                }
+                None => return Err(Error::Compiler(CompilerErr::BreakOutsideLoop,node.span)),
+            },
+
+            AstNode::Function(func) => {
+                self.compile_closure(&func.name, &func.args, func.var_args, &func.block, comp_ctx, bytecode, node.span)?;
+                bytecode.add_instr(ByteCode::Write(func_slots.next().unwrap()));
+            }
 
-            AstNode::Function(_) => {}
+            AstNode::For(for_statement) => self.compile_for(for_statement, comp_ctx, bytecode, node.span)?,
 
-            _ => todo!(),
+            AstNode::Call(expr) => expr.compile_multi_value(self, comp_ctx, bytecode, 0)?,
         }}
 
-        if let Some(instr) = encode_at_end {
-            bytecode.add_instr(instr);
+        Ok(())
+    }
+
+    /// Pushes exactly `count` values, spreading a trailing call over the remaining ones and padding with nil.
+    fn compile_expr_list(
+        &mut self,
+        exprs:&[Spanned<Expr>],
+        count:usize,
+        comp_ctx:&mut CompileCtx,
+        bytecode:&mut ByteCodeVec,
+    ) -> Result<()> {
+        for (i,expr) in exprs.iter().enumerate() {
+            if i+1 == exprs.len() && i < count && expr.is_multi_value() {
+                let ret_count = u16::try_from(count-i).ok()
+                    .filter(|x| *x != VAR_RET_COUNT)
+                    .ok_or(Error::Compiler(CompilerErr::TooManyValues,expr.span))?;
+                return expr.compile_multi_value(self, comp_ctx, bytecode, ret_count);
+            }
+
+            expr.compile(self, comp_ctx, bytecode)?;
+            if i >= count {
+                bytecode.add_instr(ByteCode::Pop);
+            }
         }
 
-        for (i,sub_func_bytecode) in self.sub_func_bytecode.iter_mut().enumerate() {
-            bytecode.add_label(self.sub_func_labels[i]);
-            bytecode.append(sub_func_bytecode);
+        for _ in exprs.len()..count {
+            bytecode.add_instr(ByteCode::LoadNil);
         }
+        Ok(())
+    }
+
+    /// The loop state lives in hidden locals, the loop variables are copied out of it every iteration.
+    fn compile_for(
+        &mut self,
+        for_statement:&ForStatement,
+        comp_ctx:&mut CompileCtx,
+        bytecode:&mut ByteCodeVec,
+        span:Span,
+    ) -> Result<()> {
+        let ForStatement { for_var1, for_var2, iter_type, table, block } = for_statement;
+
+        let max_vars = if matches!(iter_type,IterType::Range|IterType::Generic) {1} else {2};
+        if for_var2.is_some() && max_vars == 1 {
+            return Err(Error::Compiler(CompilerErr::TooManyLoopVars{ max:max_vars },span));
+        }
+
+        self.up_scope();
+        let start_label = comp_ctx.new_label();
+        let end_label = comp_ctx.new_label();
+
+        table.compile(self, comp_ctx, bytecode)?;
+        let state = self.add_local("(for state)");
+        let (idx,val) = match iter_type {
+            IterType::IPairs | IterType::Range => {
+                bytecode.add_instr(ByteCode::LoadInt(0));
+                (self.add_local("(for index)"),0)
+            }
+            IterType::KVPairs => {
+                bytecode.add_instr(ByteCode::LoadNil);
+                bytecode.add_instr(ByteCode::LoadNil);
+                (self.add_local("(for key)"),self.add_local("(for value)"))
+            }
+            IterType::Generic => (0,0),
+        };
+
+        bytecode.add_instr(ByteCode::LoadNil);
+        let var1 = self.add_local(for_var1);
+        let var2 = match for_var2 {
+            Some(name) => {
+                bytecode.add_instr(ByteCode::LoadNil);
+                Some(self.add_local(name))
+            }
+            None => None,
+        };
+
+        bytecode.add_label(start_label);
+        match iter_type {
+            IterType::IPairs | IterType::Range => {
+                bytecode.add_instr(ByteCode::Load(idx));
+                bytecode.add_instr(ByteCode::Load(state));
+                if *iter_type == IterType::IPairs {
+                    bytecode.add_instr(ByteCode::Len);
+                }
+                bytecode.add_instr(ByteCode::Less(true));
+                bytecode.add_instr(ByteCode::JumpFalse(end_label));
+
+                bytecode.add_instr(ByteCode::Load(idx));
+                bytecode.add_instr(ByteCode::Write(var1));
+                if let Some(var2) = var2 {
+                    bytecode.add_instr(ByteCode::Load(state));
+                    bytecode.add_instr(ByteCode::Load(idx));
+                    bytecode.add_instr(ByteCode::Get);
+                    bytecode.add_instr(ByteCode::Write(var2));
+                }
+            }
+
+            IterType::KVPairs => {
+                bytecode.add_instr(ByteCode::Next(state));
+                bytecode.add_instr(ByteCode::JumpFalse(end_label));
+
+                bytecode.add_instr(ByteCode::Load(idx));
+                bytecode.add_instr(ByteCode::Write(var1));
+                if let Some(var2) = var2 {
+                    bytecode.add_instr(ByteCode::Load(val));
+                    bytecode.add_instr(ByteCode::Write(var2));
+                }
+            }
+
+            IterType::Generic => {
+                bytecode.add_instr(ByteCode::LoadNil);
+                bytecode.add_instr(ByteCode::Load(state));
+                bytecode.add_instr(ByteCode::Call(CallArgs{ arg_count:0, ret_count:1 }));
+                bytecode.add_instr(ByteCode::Write(var1));
+
+                bytecode.add_instr(ByteCode::Load(var1));
+                bytecode.add_instr(ByteCode::LoadNil);
+                bytecode.add_instr(ByteCode::Eq(false));
+                bytecode.add_instr(ByteCode::JumpFalse(end_label));
+            }
+        }
+
+        let break_target = BreakTarget{ label:end_label, local_count:self.local_count() };
+        self.up_scope();
+        self.compile_block(block, comp_ctx, bytecode, Some(break_target))?;
+        self.down_scope(bytecode);
+        // Every iteration gets fresh loop variables.
+        self.close_above(var1 as usize - 1, bytecode);
+
+        if matches!(iter_type,IterType::IPairs|IterType::Range) {
+            bytecode.add_instr(ByteCode::Load(idx));
+            bytecode.add_instr(ByteCode::LoadInt(1));
+            bytecode.add_instr(ByteCode::Add);
+            bytecode.add_instr(ByteCode::Write(idx));
+        }
+        bytecode.add_instr(ByteCode::Jump(start_label));
+
+        bytecode.add_label(end_label);
+        self.down_scope(bytecode);
+        Ok(())
     }
 }
 
-impl Expr {
+impl Spanned<Expr> {
+    /// Calls and `...`, which can produce any number of values.
+    pub fn is_multi_value(&self) -> bool {
+        matches!(self.node,Expr::Call{..}|Expr::MethodCall{..}|Expr::VarArgs)
+    }
+
+    /// Compiles a call or `...` that leaves `count` values on the stack,
+    /// or all of them followed by their count if `count` is `VAR_RET_COUNT`.
+    fn compile_multi_value(
+        &self,
+        ctx:&mut FuncCtx,
+        comp_ctx:&mut CompileCtx,
+        bytecode:&mut ByteCodeVec,
+        count:u16,
+    ) -> Result<()> {
+        // The stack effect of a call that takes spread values isn't known.
+        let height = bytecode.height();
+        let results = if count == VAR_RET_COUNT {1} else {count as i32};
+        match &self.node {
+            Expr::Call { function, args } => {
+                bytecode.add_instr(ByteCode::LoadNil);
+                let arg_count = compile_spread(args, 0, ctx, comp_ctx, bytecode, self.span)?;
+                function.compile(ctx, comp_ctx, bytecode)?;
+                bytecode.add_instr(ByteCode::Call(CallArgs{ arg_count, ret_count:count }));
+                bytecode.set_height(height + results);
+            }
+
+            Expr::MethodCall { table, name, args } => {
+                // The receiver goes right above the return slot, wherever that ends up,
+                // and is passed as the first argument.
+                let receiver = height as u32 + 1;
+                bytecode.add_instr(ByteCode::LoadNil);
+                table.compile(ctx, comp_ctx, bytecode)?;
+                let arg_count = compile_spread(args, 1, ctx, comp_ctx, bytecode, self.span)?;
+
+                bytecode.add_instr(ByteCode::Load(receiver));
+                bytecode.add_instr(ByteCode::GetMethod(comp_ctx.get_idx_of_name(name)));
+                bytecode.add_instr(ByteCode::Call(CallArgs{ arg_count, ret_count:count }));
+                bytecode.set_height(height + results);
+            }
+
+            Expr::VarArgs => {
+                if !ctx.var_args {
+                    return Err(Error::Compiler(CompilerErr::VarArgsOutsideVarArgFunction,self.span));
+                }
+                bytecode.add_instr(ByteCode::LoadVarArgs(if count == VAR_RET_COUNT {VAR_COUNT} else {count}));
+            }
+
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
     pub fn compile(
         &self,
         ctx:&mut FuncCtx,
         comp_ctx:&mut CompileCtx,
         bytecode:&mut ByteCodeVec,
-    ) {
-        match self {
-            Expr::Ident(name) => comile_ident(&name, ctx, bytecode),
+    ) -> Result<()> {
+        match &self.node {
+            Expr::Ident(name) => comile_ident(name, ctx, comp_ctx, bytecode),
 
             Expr::NilLiteral => bytecode.add_instr(ByteCode::LoadNil),
             Expr::BoolLiteral(x) => bytecode.add_instr(if *x {ByteCode::LoadTrue} else {ByteCode::LoadFalse}),
             Expr::IntLiteral(x) => {
-                bytecode.add_instr(ByteCode::LoadInt(*x));
+                bytecode.add_instr(load_int(*x, self.span)?);
             }
             Expr::FloatLiteral(x) => {
                 bytecode.add_instr(ByteCode::LoadFloat(*x));
             }
             Expr::StrLiteral(x) => {
-                let idx = comp_ctx.get_idx_of_name(&x);
+                let idx = comp_ctx.get_idx_of_name(x);
                 bytecode.add_instr(ByteCode::LoadStr(idx));
             } 
 
-            Expr::Binary { op, lhs, rhs } => {
-                lhs.compile(ctx,comp_ctx,bytecode);
-                rhs.compile(ctx,comp_ctx,bytecode);
+            Expr::Binary { op:op@(Op::BoolAnd|Op::BoolOr), lhs, rhs } => {
+                // `a and b` is `a` if it is falsy and `b` otherwise, `a or b` is `a` if it is truthy.
+                let end_label = comp_ctx.new_label();
+                lhs.compile(ctx,comp_ctx,bytecode)?;
+                bytecode.add_instr(ByteCode::Dup);
                 bytecode.add_instr(match op {
-                    Op::Add    => ByteCode::Add,
-                    Op::Sub    => ByteCode::Sub,
-                    Op::Mul    => ByteCode::Mul,
-                    Op::Div    => ByteCode::Div,
-                    Op::IDiv   => ByteCode::IDiv,
-                    Op::Pow    => ByteCode::Pow,
-                    Op::Mod    => ByteCode::Mod,
-                    Op::Concat => ByteCode::Concat,
-
-                    Op::Eq        => ByteCode::Eq(true),
-                    Op::NotEq     => ByteCode::Eq(false),
-                    Op::Less      => ByteCode::Less(true),
-                    Op::Greater   => ByteCode::LessEq(false),
-                    Op::LessEq    => ByteCode::LessEq(true),
-                    Op::GreaterEq => ByteCode::Less(false),
-                    _ => todo!()
+                    Op::BoolAnd => ByteCode::JumpFalse(end_label),
+                    _ => ByteCode::JumpTrue(end_label),
                 });
+                bytecode.add_instr(ByteCode::Pop);
+                rhs.compile(ctx,comp_ctx,bytecode)?;
+                bytecode.add_label(end_label);
+            }
+
+            Expr::Binary { op, lhs, rhs } => {
+                lhs.compile(ctx,comp_ctx,bytecode)?;
+                rhs.compile(ctx,comp_ctx,bytecode)?;
+                bytecode.add_instr(op_instr(*op));
+            }
+
+            // `-2147483648` only fits into an `i32` once it is negated.
+            // Hex and binary literals already are `i32`s, so `-0x80000000` wraps around to `i32::MIN`.
+            Expr::Unary { op:UnaryOp::Neg, val } if matches!(val.node,Expr::IntLiteral(_)) => {
+                let Expr::IntLiteral(x) = val.node else { unreachable!() };
+                let x = if x == i32::MIN as i64 {x} else {-x};
+                bytecode.add_instr(load_int(x, val.span)?);
             }
 
             Expr::Unary { op, val } => {
-                val.compile(ctx,comp_ctx,bytecode);
+                val.compile(ctx,comp_ctx,bytecode)?;
                 bytecode.add_instr(match op {
                     UnaryOp::Neg => ByteCode::Neg,
                     UnaryOp::Not => ByteCode::Not,
@@ -334,86 +613,135 @@ impl Expr {
                 });
             }
 
-            Expr::Call { function, args } => {
-                bytecode.add_instr(ByteCode::LoadNil);
-                for arg in args {
-                    arg.compile(ctx, comp_ctx, bytecode);
-                }
-
-                function.compile(ctx, comp_ctx, bytecode);
-                
-                bytecode.add_instr(ByteCode::Call(args.len() as u16));
-            }
-
-            Expr::MethodCall { table, name, args } => {
-
-                bytecode.add_instr(ByteCode::LoadNil);
-                table.compile(ctx, comp_ctx, bytecode);
-                for arg in args {
-                    arg.compile(ctx, comp_ctx, bytecode);
-                }
-
-                bytecode.add_instr(ByteCode::Load(ctx.local_count() as u16 + 2));
-                bytecode.add_instr(ByteCode::GetMethod(comp_ctx.get_idx_of_name(&name)));
-                bytecode.add_instr(ByteCode::Call(args.len() as u16));
-            }
+            Expr::Call {..} | Expr::MethodCall {..} | Expr::VarArgs => self.compile_multi_value(ctx, comp_ctx, bytecode, 1)?,
 
             Expr::Index { table, idx } => {
-                table.compile(ctx, comp_ctx, bytecode);
-                idx.compile(ctx, comp_ctx, bytecode);
+                table.compile(ctx, comp_ctx, bytecode)?;
+                idx.compile(ctx, comp_ctx, bytecode)?;
                 bytecode.add_instr(ByteCode::Get);
             }
 
             Expr::TableLiteral(table) => {
-                bytecode.add_instr(ByteCode::NewTable(table.arr.len() as u16));
+                let cap = u32::try_from(table.arr.len()).map_err(|_| Error::Compiler(CompilerErr::TooManyValues,self.span))?;
+                bytecode.add_instr(ByteCode::NewTable(cap));
 
-                for expr in &table.arr {
-                    expr.compile(ctx, comp_ctx, bytecode);
-                    bytecode.add_instr(ByteCode::Push);
+                for (i,expr) in table.arr.iter().enumerate() {
+                    if i+1 == table.arr.len() && expr.is_multi_value() {
+                        expr.compile_multi_value(ctx, comp_ctx, bytecode, VAR_RET_COUNT)?;
+                        bytecode.add_instr(ByteCode::PushAll);
+                    } else {
+                        expr.compile(ctx, comp_ctx, bytecode)?;
+                        bytecode.add_instr(ByteCode::Push);
+                    }
                 }
 
                 for (k,v) in &table.map {
                     bytecode.add_instr(match k {
                         TableLiteralIdx::BoolLiteral(x) => if *x {ByteCode::LoadTrue} else {ByteCode::LoadFalse},
-                        TableLiteralIdx::IntLiteral(x) => ByteCode::LoadInt(*x),
+                        TableLiteralIdx::IntLiteral(x) => load_int(*x, self.span)?,
                         TableLiteralIdx::FloatLiteral(x) => ByteCode::LoadFloat(*x),
                         TableLiteralIdx::StrLiteral(x) => ByteCode::LoadStr(comp_ctx.get_idx_of_name(x)),
                     });
-                    v.compile(ctx, comp_ctx, bytecode);
+                    v.compile(ctx, comp_ctx, bytecode)?;
                     bytecode.add_instr(ByteCode::Set);
                 }
             }
 
-            Expr::Function(InlineFunction { args, block }) => {
-                let mut func_bytecode = ByteCodeVec::new();
-                let mut sub_func_ctx = FuncCtx::new(args);
-                sub_func_ctx.prev = Some(ctx);
-                sub_func_ctx.compile(block, comp_ctx, &mut func_bytecode, Some(ByteCode::Ret), None);
-
-                ctx.sub_func_bytecode.push(func_bytecode);
-                let label = comp_ctx.new_label();
-                ctx.sub_func_labels.push(label);
-
-                bytecode.add_instr(ByteCode::Closure(ClosureArgs{
-                    label, 
-                    upval_cap: sub_func_ctx.upvals.len() as u8,
-                    arg_count: sub_func_ctx.args.len() as u8
-                }));
-
-                for (i,upval) in sub_func_ctx.upvals.iter().enumerate() {
-                    comile_ident(&upval, ctx, bytecode);
-                    bytecode.add_instr(ByteCode::BindUpval(i as u16));
-                }
+            Expr::Function(InlineFunction { args, var_args, block }) => {
+                ctx.compile_closure("(anonymous)", args, *var_args, block, comp_ctx, bytecode, self.span)?;
             }
         }
+        Ok(())
     }
 }
 
 
-fn comile_ident(name:&str,ctx:&mut FuncCtx,bytecode:&mut ByteCodeVec) {
+/// Pushes every expression, spreading a trailing call or `...`.
+/// The `pushed` values right below them, like a method receiver, are counted too.
+/// Returns the number of values, or `VAR_COUNT` if it is on top of the stack.
+fn compile_spread(
+    exprs:&[Spanned<Expr>],
+    pushed:usize,
+    ctx:&mut FuncCtx,
+    comp_ctx:&mut CompileCtx,
+    bytecode:&mut ByteCodeVec,
+    span:Span,
+) -> Result<u16> {
+    let (rest,spread) = match exprs.split_last() {
+        Some((last,rest)) if last.is_multi_value() => (rest,Some(last)),
+        _ => (exprs,None),
+    };
+    let fixed_count = i32::try_from(pushed+rest.len()).map_err(|_| Error::Compiler(CompilerErr::TooManyValues,span))?;
+
+    for expr in rest {
+        expr.compile(ctx, comp_ctx, bytecode)?;
+    }
+
+    let Some(last) = spread else {
+        // Counts that don't fit into the instruction are passed like spread values.
+        return match u16::try_from(fixed_count) {
+            Ok(count) if count != VAR_COUNT => Ok(count),
+            _ => {
+                bytecode.add_instr(ByteCode::LoadInt(fixed_count));
+                Ok(VAR_COUNT)
+            }
+        };
+    };
+
+    last.compile_multi_value(ctx, comp_ctx, bytecode, VAR_RET_COUNT)?;
+    if fixed_count != 0 {
+        bytecode.add_instr(ByteCode::LoadInt(fixed_count));
+        bytecode.add_instr(ByteCode::Add);
+    }
+    Ok(VAR_COUNT)
+}
+
+/// Integer literals are range checked here, so the sign in front of them counts.
+fn load_int(x:i64,span:Span) -> Result<ByteCode> {
+    i32::try_from(x).map(ByteCode::LoadInt).map_err(|_| Error::Compiler(CompilerErr::IntOverflow,span))
+}
+
+/// The instruction of a binary operator, `and` and `or` jump instead.
+fn op_instr(op:Op) -> ByteCode {
+    match op {
+        Op::Add    => ByteCode::Add,
+        Op::Sub    => ByteCode::Sub,
+        Op::Mul    => ByteCode::Mul,
+        Op::Div    => ByteCode::Div,
+        Op::IDiv   => ByteCode::IDiv,
+        Op::Pow    => ByteCode::Pow,
+        Op::Mod    => ByteCode::Mod,
+        Op::Concat => ByteCode::Concat,
+
+        Op::Eq        => ByteCode::Eq(true),
+        Op::NotEq     => ByteCode::Eq(false),
+        Op::Less      => ByteCode::Less(true),
+        Op::Greater   => ByteCode::LessEq(false),
+        Op::LessEq    => ByteCode::LessEq(true),
+        Op::GreaterEq => ByteCode::Less(false),
+
+        Op::And => ByteCode::And,
+        Op::Or  => ByteCode::Or,
+        Op::Xor => ByteCode::Xor,
+        Op::Shl => ByteCode::Shl,
+        Op::Shr => ByteCode::Shr,
+
+        Op::BoolAnd | Op::BoolOr => unreachable!(),
+    }
+}
+
+fn write_ident(name:&str,ctx:&mut FuncCtx,comp_ctx:&mut CompileCtx,bytecode:&mut ByteCodeVec) {
+    match ctx.kind_of_ident(name) {
+        VarKind::Local(id) => bytecode.add_instr(ByteCode::Write(id+1)),
+        VarKind::Global(name) => bytecode.add_instr(ByteCode::SetGlobal(comp_ctx.get_idx_of_name(&name))),
+        VarKind::Upval(id) => bytecode.add_instr(ByteCode::SetUpval(id)),
+    }
+}
+
+fn comile_ident(name:&str,ctx:&mut FuncCtx,comp_ctx:&mut CompileCtx,bytecode:&mut ByteCodeVec) {
     match ctx.kind_of_ident(name) {
         VarKind::Local(id) => bytecode.add_instr(ByteCode::Load(id+1)),
-        VarKind::Global(_) => todo!(),
+        VarKind::Global(name) => bytecode.add_instr(ByteCode::GetGlobal(comp_ctx.get_idx_of_name(&name))),
         VarKind::Upval(id) => bytecode.add_instr(ByteCode::GetUpval(id)),
     }
 }
@@ -0,0 +1,141 @@
+use std::fmt::Write;
+
+use crate::{err::{CompilerErr, Error, ParserErr, TokenizerErr}, span::Span};
+
+const TAB_WIDTH:usize = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message:String,
+    pub span:Option<Span>,
+    pub notes:Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(message:impl Into<String>,span:Option<Span>) -> Self {
+        Self { message:message.into(), span, notes:vec![] }
+    }
+
+    pub fn with_note(mut self,note:impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// Renders the diagnostic with the offending source line and the span underlined.
+    /// Spans that lie outside of `src` or inside a multi-byte char are clamped.
+    pub fn render(&self,src:&str,file_name:&str) -> String {
+        let mut out = String::new();
+        _ = writeln!(out,"error: {}",self.message);
+
+        let Some(span) = self.span else {
+            for note in &self.notes {
+                _ = writeln!(out,"  = note: {}",note);
+            }
+            return out;
+        };
+
+        let start = floor_char_boundary(src, span.start.min(src.len()));
+        let end = floor_char_boundary(src, span.end.clamp(start, src.len()));
+
+        let line_start = src[..start].rfind('\n').map_or(0, |i| i+1);
+        let line_end = src[start..].find('\n').map_or(src.len(), |i| start+i);
+        let line_no = src[..line_start].matches('\n').count()+1;
+        let line = src[line_start..line_end].trim_end_matches('\r');
+
+        let col = display_width(&src[line_start..start]);
+        let underline = display_width(&src[start..end.min(line_end).max(start)]).max(1);
+
+        let gutter = " ".repeat(line_no.to_string().len());
+        _ = writeln!(out,"{}--> {}:{}:{}",gutter,file_name,line_no,col+1);
+        _ = writeln!(out,"{} |",gutter);
+        _ = writeln!(out,"{} | {}",line_no,line.replace('\t', &" ".repeat(TAB_WIDTH)));
+        _ = writeln!(out,"{} | {}{}",gutter," ".repeat(col),"^".repeat(underline));
+        for note in &self.notes {
+            _ = writeln!(out,"{} = note: {}",gutter,note);
+        }
+        out
+    }
+}
+
+impl From<&Error> for Diagnostic {
+    fn from(err:&Error) -> Self {
+        let diagnostic = Diagnostic::new(err.to_string(), err.span());
+        match err {
+            Error::Parser(ParserErr::UnmatchedBracket(_),_) => diagnostic.with_note("every bracket needs a matching closing bracket"),
+            Error::Tokenizer(TokenizerErr::UnterminatedStr,_) => diagnostic.with_note("strings end at the end of the line, `\"\"\"` strings can span multiple lines"),
+            Error::Tokenizer(TokenizerErr::InvalidEscape(_),_) => diagnostic.with_note("valid escapes are `\\n`, `\\t`, `\\r`, `\\0`, `\\\\`, `\\\"`, `\\'`, `\\x00` to `\\x7F` and `\\u{...}`"),
+            Error::Compiler(CompilerErr::BreakOutsideLoop,_) => diagnostic.with_note("`break` can only be used inside of `while` and `for` loops"),
+            _ => diagnostic,
+        }
+    }
+}
+
+/// Renders every diagnostic, separated by an empty line, followed by a summary line.
+pub fn render_all(diagnostics:&[Diagnostic],src:&str,file_name:&str) -> String {
+    let mut out = String::new();
+    for diagnostic in diagnostics {
+        out.push_str(&diagnostic.render(src, file_name));
+        out.push('\n');
+    }
+    match diagnostics.len() {
+        0 => {},
+        1 => out.push_str("error: could not compile due to 1 previous error\n"),
+        n => _ = writeln!(out,"error: could not compile due to {} previous errors",n),
+    }
+    out
+}
+
+fn floor_char_boundary(src:&str,mut idx:usize) -> usize {
+    while !src.is_char_boundary(idx) {
+        idx -= 1;
+    }
+    idx
+}
+
+fn display_width(str:&str) -> usize {
+    str.chars().map(|c| if c == '\t' {TAB_WIDTH} else {1}).sum()
+}
+
+
+#[test]
+fn render_test() {
+    let src = "local x = 1;\nlocal y = x +;\n";
+    let diagnostic = Diagnostic::new("expected expression", Some(Span::new(26,27,2,14)))
+        .with_note("a note");
+    assert_eq!(diagnostic.render(src, "test.mu"), "\
+error: expected expression
+ --> test.mu:2:14
+  |
+2 | local y = x +;
+  |              ^
+  = note: a note
+");
+}
+
+#[test]
+fn render_robust_test() {
+    let src = "local s = \"äöü\";";
+    for start in 0..src.len()+2 {
+        for end in start..src.len()+2 {
+            Diagnostic::new("msg", Some(Span::new(start,end,1,1))).render(src, "test.mu");
+        }
+    }
+    Diagnostic::new("msg", Some(Span::new(0,1,1,1))).render("", "test.mu");
+
+    let out = Diagnostic::new("msg", Some(Span::new(12,14,1,1))).render(src, "test.mu");
+    assert!(out.contains("1 | local s = \"äöü\";\n  |            ^\n"));
+}
+
+#[test]
+fn error_diagnostic_test() {
+    let src = "local x = 1;\nwhile x {\n  break;\n}\nbreak;";
+    let errors = [
+        Error::Compiler(CompilerErr::BreakOutsideLoop, Span::new(34,40,5,1)),
+        Error::Parser(ParserErr::EmptyExpr, Span::new(10,11,1,11)),
+    ];
+    let diagnostics:Vec<Diagnostic> = errors.iter().map(Diagnostic::from).collect();
+    let out = render_all(&diagnostics, src, "test.mu");
+    assert!(out.contains("5 | break;\n  | ^^^^^^\n  = note: `break` can only be used"));
+    assert!(out.contains("1 | local x = 1;\n  |           ^\n"));
+    assert!(out.ends_with("could not compile due to 2 previous errors\n"));
+}
@@ -0,0 +1,293 @@
+use std::fmt::Write;
+
+use crate::{asm::{instr_width, wide_operand, ByteCodeVec, FuncProto, LabelId, Layout, UpvalDesc}, bytecode::{ByteCode, CallArgs, EXTEND_ARG, JUMP_FALSE_LONG, JUMP_LONG, JUMP_TRUE_LONG, VAR_COUNT, VAR_RET_COUNT}, err::{Error, FormatErr, Result}, lout::{LoutFile, SectionKind}};
+
+/// A decoded `.lout` file.
+pub struct Program {
+    pub names:Vec<Box<str>>,
+    pub main:FuncProto,
+    pub protos:Vec<FuncProto>,
+}
+
+impl Program {
+    pub fn decode(bytes:&[u8]) -> Result<Self> {
+        let file = LoutFile::read(bytes)?;
+        let names = file.names()?.into_iter().map(Into::into).collect();
+
+        let mut main = Reader{ bytes:file.section(SectionKind::Main)?, pos:0 };
+        let main = main.proto()?;
+
+        let mut protos = Reader{ bytes:file.section(SectionKind::Protos)?, pos:0 };
+        let protos = (0..protos.u32()?).map(|_| protos.proto()).collect::<Result<_>>()?;
+
+        Ok(Self { names, main, protos })
+    }
+
+    /// Lists every function with labels, word offsets and comments for names and closures.
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        self.disassemble_proto(&self.main, "main", &mut out);
+        for (i,proto) in self.protos.iter().enumerate() {
+            out.push('\n');
+            self.disassemble_proto(proto, &i.to_string(), &mut out);
+        }
+        out
+    }
+
+    fn disassemble_proto(&self,proto:&FuncProto,id:&str,out:&mut String) {
+        _ = writeln!(out,"function {} `{}`: args={} var_args={} max_stack={}",
+            id,proto.name,proto.arg_count,proto.var_args,proto.max_stack);
+        for (i,upval) in proto.upvals.iter().enumerate() {
+            match upval {
+                UpvalDesc::Local(x) => _ = writeln!(out,"    upval {} = local {}",i,x),
+                UpvalDesc::Upval(x) => _ = writeln!(out,"    upval {} = upval {}",i,x),
+            }
+        }
+
+        let code = &proto.code;
+        let layout = Layout::of(code);
+        for i in 0..=code.len() {
+            for (label,_) in code.labels().iter().filter(|x| x.1 == i) {
+                _ = writeln!(out,"L{}:",label.index());
+            }
+            let Some(instr) = code.instrs().get(i) else {
+                break;
+            };
+
+            let mut line = format!("    {:04}  {}",layout.offsets[i],instr.mnemonic());
+            let operands = operands(instr);
+            if !operands.is_empty() {
+                line.push(' ');
+                line.push_str(&operands);
+            }
+            if let Some(comment) = self.comment(instr) {
+                _ = write!(line,"{:width$}; ","",width=36usize.saturating_sub(line.len()));
+                line.push_str(&comment);
+            }
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+
+    fn comment(&self,instr:&ByteCode) -> Option<String> {
+        let name = |i:u32| self.names.get(i as usize).map_or("<invalid name>", |x| &**x);
+        match *instr {
+            ByteCode::LoadStr(i) => Some(format!("{:?}",name(i))),
+            ByteCode::GetGlobal(i) | ByteCode::SetGlobal(i) | ByteCode::GetMethod(i) => Some(name(i).to_string()),
+            ByteCode::Closure(i) => Some(match self.protos.get(i as usize) {
+                Some(proto) => format!("function {} `{}`",i,proto.name),
+                None => "<invalid function>".to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn operands(instr:&ByteCode) -> String {
+    let count = |x:u16| if x == VAR_COUNT {"var".to_string()} else {x.to_string()};
+    match *instr {
+        ByteCode::LoadInt(x) => x.to_string(),
+        ByteCode::LoadFloat(x) => format!("{:?}",x),
+
+        ByteCode::Load(x) | ByteCode::Write(x) | ByteCode::LoadStr(x) |
+        ByteCode::BindUpval(x) | ByteCode::GetUpval(x) | ByteCode::SetUpval(x) |
+        ByteCode::GetMethod(x) | ByteCode::NewTable(x) | ByteCode::Next(x) |
+        ByteCode::GetGlobal(x) | ByteCode::SetGlobal(x) | ByteCode::Close(x) | ByteCode::Closure(x) => x.to_string(),
+
+        ByteCode::Ret(x) | ByteCode::LoadVarArgs(x) => count(x),
+        ByteCode::Call(CallArgs { arg_count, ret_count }) => {
+            let ret_count = if ret_count == VAR_RET_COUNT {"var".to_string()} else {ret_count.to_string()};
+            format!("{} {}",count(arg_count),ret_count)
+        }
+
+        ByteCode::Less(x) | ByteCode::LessEq(x) | ByteCode::Eq(x) => x.to_string(),
+        ByteCode::Jump(x) | ByteCode::JumpTrue(x) | ByteCode::JumpFalse(x) => format!("L{}",x.index()),
+        _ => String::new(),
+    }
+}
+
+struct Reader<'a> {
+    bytes:&'a [u8],
+    pos:usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self,len:usize) -> Result<&'a [u8]> {
+        let bytes = self.bytes.get(self.pos..self.pos+len).ok_or(Error::Format(FormatErr::Truncated))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<&'a str> {
+        let len = self.bytes[self.pos..].iter().position(|x| *x == 0).ok_or(Error::Format(FormatErr::Truncated))?;
+        let str = std::str::from_utf8(self.take(len)?).map_err(|_| Error::Format(FormatErr::InvalidName))?;
+        self.pos += 1;
+        Ok(str)
+    }
+
+    fn proto(&mut self) -> Result<FuncProto> {
+        let name = self.str()?.into();
+        let arg_count = self.u16()?;
+        let var_args = self.u8()? != 0;
+        let max_stack = self.u32()?;
+
+        let upvals = (0..self.u32()?).map(|_| {
+            let kind = self.u8()?;
+            let idx = self.u32()?;
+            match kind {
+                0 => Ok(UpvalDesc::Local(idx)),
+                1 => Ok(UpvalDesc::Upval(idx)),
+                _ => Err(Error::Format(FormatErr::InvalidUpvalKind(kind))),
+            }
+        }).collect::<Result<_>>()?;
+
+        let len = self.u32()? as usize;
+        let words = self.take(len.checked_mul(4).ok_or(Error::Format(FormatErr::Truncated))?)?
+            .chunks_exact(4)
+            .map(|x| u32::from_ne_bytes(x.try_into().unwrap()))
+            .collect::<Vec<u32>>();
+        let code = decode_code(&words)?;
+
+        Ok(FuncProto { name, code, arg_count, var_args, upvals, max_stack })
+    }
+}
+
+/// Turns encoded words back into instructions, jumps get a label for every target.
+pub fn decode_code(words:&[u32]) -> Result<ByteCodeVec> {
+    let invalid_target = || Error::Format(FormatErr::InvalidJumpTarget);
+
+    // Instructions with the word their jump goes to.
+    let mut instrs:Vec<(ByteCode,Option<usize>)> = vec![];
+    let mut instr_offsets = vec![];
+    let mut i = 0;
+    while i < words.len() {
+        instr_offsets.push(i);
+        let start = i;
+
+        // The upper half of the operand of the next instruction.
+        let mut ext = None;
+        if let [b0,b1,EXTEND_ARG,_] = words[i].to_ne_bytes() {
+            ext = Some((u16::from_le_bytes([b0,b1]) as u32) << 16);
+            i += 1;
+        }
+
+        let Some(word) = words.get(i) else {
+            return Err(Error::Format(FormatErr::Truncated));
+        };
+        let [b0,b1,op,_] = word.to_ne_bytes();
+        let x = u16::from_le_bytes([b0,b1]);
+        let wide = ext.unwrap_or(0) | x as u32;
+        let next_word = || words.get(i+1).copied().ok_or(Error::Format(FormatErr::Truncated));
+        let target = |width:usize,offset:isize| (i as isize + width as isize).checked_add(offset)
+            .and_then(|x| usize::try_from(x).ok())
+            .ok_or_else(invalid_target);
+        let long_target = || target(2, next_word()? as i32 as isize);
+
+        let (instr,target) = match op {
+            0  => (ByteCode::LoadNil,None),
+            1  => (ByteCode::LoadTrue,None),
+            2  => (ByteCode::LoadFalse,None),
+            3  => (ByteCode::LoadInt(next_word()? as i32),None),
+            4  => (ByteCode::LoadFloat(f32::from_bits(next_word()?)),None),
+            6  => (ByteCode::LoadStr(wide),None),
+            7  => (ByteCode::Load(wide),None),
+            8  => (ByteCode::Write(wide),None),
+            9  => (ByteCode::Add,None),
+            10 => (ByteCode::Sub,None),
+            11 => (ByteCode::Mul,None),
+            12 => (ByteCode::Div,None),
+            13 => (ByteCode::IDiv,None),
+            14 => (ByteCode::Pow,None),
+            15 => (ByteCode::Mod,None),
+            16 => (ByteCode::Concat,None),
+            17 => (ByteCode::Closure(wide),None),
+            18 => (ByteCode::Call(CallArgs{ arg_count:x, ret_count:next_word()? as u16 }),None),
+            19 => (ByteCode::Ret(x),None),
+            20 => (ByteCode::BindUpval(wide),None),
+            21 => (ByteCode::GetUpval(wide),None),
+            22 => (ByteCode::SetUpval(wide),None),
+            23 => (ByteCode::Jump(LabelId::nth(0)),Some(target(1, x as i16 as isize)?)),
+            24 => (ByteCode::JumpTrue(LabelId::nth(0)),Some(target(1, x as i16 as isize)?)),
+            25 => (ByteCode::JumpFalse(LabelId::nth(0)),Some(target(1, x as i16 as isize)?)),
+            JUMP_LONG => (ByteCode::Jump(LabelId::nth(0)),Some(long_target()?)),
+            JUMP_TRUE_LONG => (ByteCode::JumpTrue(LabelId::nth(0)),Some(long_target()?)),
+            JUMP_FALSE_LONG => (ByteCode::JumpFalse(LabelId::nth(0)),Some(long_target()?)),
+            26 => (ByteCode::Less(b0 != 0),None),
+            27 => (ByteCode::LessEq(b0 != 0),None),
+            28 => (ByteCode::Eq(b0 != 0),None),
+            30 => (ByteCode::Halt,None),
+            31 => (ByteCode::And,None),
+            32 => (ByteCode::Or,None),
+            33 => (ByteCode::Xor,None),
+            34 => (ByteCode::BoolAnd,None),
+            35 => (ByteCode::BoolOr,None),
+            36 => (ByteCode::Neg,None),
+            37 => (ByteCode::Not,None),
+            38 => (ByteCode::BoolNot,None),
+            39 => (ByteCode::Len,None),
+            40 => (ByteCode::Shl,None),
+            41 => (ByteCode::Shr,None),
+            42 => (ByteCode::NewTable(wide),None),
+            43 => (ByteCode::Pop,None),
+            44 => (ByteCode::Get,None),
+            46 => (ByteCode::Set,None),
+            47 => (ByteCode::SetPop,None),
+            48 => (ByteCode::Push,None),
+            49 => (ByteCode::GetMethod(wide),None),
+            50 => (ByteCode::GetGlobal(wide),None),
+            51 => (ByteCode::SetGlobal(wide),None),
+            52 => (ByteCode::Next(wide),None),
+            53 => (ByteCode::Dup,None),
+            54 => (ByteCode::LoadVarArgs(x),None),
+            55 => (ByteCode::PushAll,None),
+            58 => (ByteCode::Close(wide),None),
+            _ => return Err(Error::Format(FormatErr::InvalidOpcode(op))),
+        };
+        if ext.is_some() && wide_operand(&instr).is_none_or(|x| x <= u16::MAX as u32) {
+            return Err(Error::Format(FormatErr::InvalidExtendArg));
+        }
+        i = start + instr_width(&instr) + matches!(op, JUMP_LONG | JUMP_TRUE_LONG | JUMP_FALSE_LONG) as usize;
+        instrs.push((instr,target));
+    }
+    instr_offsets.push(words.len());
+
+    // Labels are numbered in the order of the instructions they point at.
+    let mut targets:Vec<usize> = instrs.iter().filter_map(|x| x.1).collect();
+    targets.sort();
+    targets.dedup();
+    let mut target_instrs = vec![];
+    for target in &targets {
+        target_instrs.push(instr_offsets.binary_search(target).map_err(|_| invalid_target())?);
+    }
+    let label_of = |target:usize| LabelId::nth(targets.binary_search(&target).unwrap() as u32);
+
+    let mut code = ByteCodeVec::new();
+    for (i,(instr,target)) in instrs.into_iter().enumerate() {
+        for (n,_) in target_instrs.iter().enumerate().filter(|x| *x.1 == i) {
+            code.add_label(LabelId::nth(n as u32));
+        }
+        code.add_instr(match (instr,target) {
+            (ByteCode::Jump(_),Some(x)) => ByteCode::Jump(label_of(x)),
+            (ByteCode::JumpTrue(_),Some(x)) => ByteCode::JumpTrue(label_of(x)),
+            (ByteCode::JumpFalse(_),Some(x)) => ByteCode::JumpFalse(label_of(x)),
+            (instr,_) => instr,
+        });
+    }
+    let end = code.len();
+    for (n,_) in target_instrs.iter().enumerate().filter(|x| *x.1 == end) {
+        code.add_label(LabelId::nth(n as u32));
+    }
+    Ok(code)
+}
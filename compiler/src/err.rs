@@ -1,6 +1,202 @@
+use std::fmt;
+
+use crate::{span::Span, tokenizer::Token};
+
 #[derive(Debug)]
 pub enum Error {
-    
+    Tokenizer(TokenizerErr,Span),
+    Parser(ParserErr,Span),
+    Compiler(CompilerErr,Span),
+    Format(FormatErr),
+    Asm(AsmErr,Span),
+    Io(std::io::Error),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenizerErr {
+    InvalidSymbol(char),
+    UnterminatedStr,
+    InvalidEscape(Box<str>),
+    UnterminatedComment,
+    InvalidNumber(&'static str),
+    IntOverflow,
+    FloatOverflow,
+    FloatUnderflow,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParserErr {
+    UnexpectedToken{found:Token,expected:&'static str},
+    UnexpectedEnd{expected:&'static str},
+    UnmatchedBracket(Token),
+    InvalidTableKey(Token),
+    EmptyExpr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompilerErr {
+    BreakOutsideLoop,
+    InvalidAssingTarget,
+    TooManyLoopVars{max:usize},
+    TooManyValues,
+    TooManyParams{max:usize},
+    IntOverflow,
+    VarArgsOutsideVarArgFunction,
+    Unsupported(&'static str),
+}
+
+/// A `.lout` file that can't be read or written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatErr {
+    BadMagic,
+    UnsupportedVersion(u16),
+    WrongEndianness,
+    Truncated,
+    TrailingBytes,
+    ChecksumMismatch{expected:u32,found:u32},
+    UnknownSection(u32),
+    BadSectionTable,
+    MissingSection(&'static str),
+    InvalidName,
+    InvalidOpcode(u8),
+    InvalidUpvalKind(u8),
+    InvalidJumpTarget,
+    InvalidExtendArg,
+    TooLarge,
+}
+
+/// Invalid `.masm` assembly.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AsmErr {
+    UnknownInstruction(Box<str>),
+    UnknownDirective(Box<str>),
+    MissingOperand{expected:&'static str},
+    InvalidOperand{expected:&'static str},
+    UnexpectedOperand,
+    UnterminatedString,
+    InvalidEscape(char),
+    UnknownLabel(Box<str>),
+    DuplicateLabel(Box<str>),
+    UnknownFunction(Box<str>),
+    DuplicateFunction(Box<str>),
+    NestedFunction,
+    UnclosedFunction,
+    OutsideOfFunction(&'static str),
+}
+
+pub type Result<T> = std::result::Result<T,Error>;
+
+impl Error {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Error::Tokenizer(_,span) | Error::Parser(_,span) | Error::Compiler(_,span) | Error::Asm(_,span) => Some(*span),
+            Error::Format(_) | Error::Io(_) => None,
+        }
+    }
 }
 
-pub type Result<T> = std::result::Result<T,Error>;
\ No newline at end of file
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Tokenizer(err,_) => write!(f,"{}",err),
+            Error::Parser(err,_) => write!(f,"{}",err),
+            Error::Compiler(err,_) => write!(f,"{}",err),
+            Error::Format(err) => write!(f,"{}",err),
+            Error::Asm(err,_) => write!(f,"{}",err),
+            Error::Io(err) => write!(f,"io error: {}",err),
+        }
+    }
+}
+
+impl fmt::Display for TokenizerErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenizerErr::InvalidSymbol(x) => write!(f,"invalid symbol `{}`",x.escape_debug()),
+            TokenizerErr::UnterminatedStr => write!(f,"unterminated string"),
+            TokenizerErr::InvalidEscape(x) => write!(f,"invalid escape sequence `{}`",x),
+            TokenizerErr::UnterminatedComment => write!(f,"unterminated block comment"),
+            TokenizerErr::InvalidNumber(x) => write!(f,"invalid number, {}",x),
+            TokenizerErr::IntOverflow => write!(f,"integer literal does not fit into 32 bits"),
+            TokenizerErr::FloatOverflow => write!(f,"float literal is too large"),
+            TokenizerErr::FloatUnderflow => write!(f,"float literal is too small, it would round to 0"),
+        }
+    }
+}
+
+impl fmt::Display for ParserErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParserErr::UnexpectedToken { found, expected } => write!(f,"expected {}, found {:?}",expected,found),
+            ParserErr::UnexpectedEnd { expected } => write!(f,"expected {}",expected),
+            ParserErr::UnmatchedBracket(x) => write!(f,"unmatched bracket {:?}",x),
+            ParserErr::InvalidTableKey(x) => write!(f,"{:?} can not be used as a table key",x),
+            ParserErr::EmptyExpr => write!(f,"expected expression"),
+        }
+    }
+}
+
+impl fmt::Display for CompilerErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompilerErr::BreakOutsideLoop => write!(f,"`break` outside of a loop"),
+            CompilerErr::InvalidAssingTarget => write!(f,"can only assign to variables and table fields"),
+            CompilerErr::TooManyLoopVars { max } => write!(f,"this loop takes at most {} loop variable{}",max,if *max == 1 {""} else {"s"}),
+            CompilerErr::TooManyValues => write!(f,"too many values"),
+            CompilerErr::TooManyParams { max } => write!(f,"functions take at most {} parameters",max),
+            CompilerErr::IntOverflow => write!(f,"integer literal does not fit into an i32"),
+            CompilerErr::VarArgsOutsideVarArgFunction => write!(f,"`...` can only be used inside of functions that take `...`"),
+            CompilerErr::Unsupported(what) => write!(f,"{} is not supported yet",what),
+        }
+    }
+}
+
+impl fmt::Display for FormatErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatErr::BadMagic => write!(f,"not a .lout file"),
+            FormatErr::UnsupportedVersion(x) => write!(f,"unsupported .lout format version {}, expected {}",x,crate::lout::FORMAT_VERSION),
+            FormatErr::WrongEndianness => write!(f,".lout file was written on a machine with a different endianness"),
+            FormatErr::Truncated => write!(f,".lout file is truncated"),
+            FormatErr::TrailingBytes => write!(f,".lout file has trailing bytes"),
+            FormatErr::ChecksumMismatch { expected, found } => write!(f,".lout file is corrupted, checksum is {:#010x} but should be {:#010x}",found,expected),
+            FormatErr::UnknownSection(x) => write!(f,"unknown .lout section {}",x),
+            FormatErr::BadSectionTable => write!(f,".lout section table is invalid"),
+            FormatErr::MissingSection(x) => write!(f,".lout file has no {} section",x),
+            FormatErr::InvalidName => write!(f,".lout name contains invalid utf-8"),
+            FormatErr::InvalidOpcode(x) => write!(f,"invalid opcode {}",x),
+            FormatErr::InvalidUpvalKind(x) => write!(f,"invalid upvalue kind {}",x),
+            FormatErr::InvalidJumpTarget => write!(f,"jump into the middle of an instruction or out of the function"),
+            FormatErr::InvalidExtendArg => write!(f,"extend_arg prefix on an operand that fits into 16 bits"),
+            FormatErr::TooLarge => write!(f,"program does not fit into a .lout file, which is limited to 4 GiB"),
+        }
+    }
+}
+
+impl fmt::Display for AsmErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AsmErr::UnknownInstruction(x) => write!(f,"unknown instruction `{}`",x),
+            AsmErr::UnknownDirective(x) => write!(f,"unknown directive `{}`",x),
+            AsmErr::MissingOperand { expected } => write!(f,"expected {}",expected),
+            AsmErr::InvalidOperand { expected } => write!(f,"invalid operand, expected {}",expected),
+            AsmErr::UnexpectedOperand => write!(f,"unexpected operand"),
+            AsmErr::UnterminatedString => write!(f,"unterminated string"),
+            AsmErr::InvalidEscape(x) => write!(f,"invalid escape `\\{}`",x),
+            AsmErr::UnknownLabel(x) => write!(f,"unknown label `{}`",x),
+            AsmErr::DuplicateLabel(x) => write!(f,"label `{}` is defined more than once",x),
+            AsmErr::UnknownFunction(x) => write!(f,"unknown function `{}`",x),
+            AsmErr::DuplicateFunction(x) => write!(f,"function `{}` is defined more than once",x),
+            AsmErr::NestedFunction => write!(f,"functions can not be nested, `.end` the previous one first"),
+            AsmErr::UnclosedFunction => write!(f,"function is missing its `.end`"),
+            AsmErr::OutsideOfFunction(x) => write!(f,"`{}` outside of a function",x),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
@@ -1,6 +1,4 @@
-use std::{u32, usize};
-
-use crate::{ast_gen::{self,Block}, err::Result, tokenizer::Token};
+use crate::{ast_gen::{Block,Parser}, err::{Error, ParserErr, Result}, span::{Span, Spanned}, tokenizer::Token};
 
 #[derive(Debug,Clone, Copy)]
 pub enum Op {
@@ -16,6 +14,8 @@ pub enum Op {
     And,
     Or,
     Xor,
+    Shl,
+    Shr,
 
     Less,
     LessEq,
@@ -40,46 +40,59 @@ pub enum UnaryOp {
 pub enum Expr {
     NilLiteral,
     BoolLiteral(bool),
-    IntLiteral(i32),
+    IntLiteral(i64),
     FloatLiteral(f32),
     StrLiteral(Box<str>),
     TableLiteral(TableLiteral),
     Function(InlineFunction),
 
     Ident(Box<str>),
+    /// `...`
+    VarArgs,
 
-    Binary{op:Op,lhs:Box<Self>,rhs:Box<Self>},
-    Unary{op:UnaryOp,val:Box<Self>},
+    Binary{op:Op,lhs:Box<Spanned<Self>>,rhs:Box<Spanned<Self>>},
+    Unary{op:UnaryOp,val:Box<Spanned<Self>>},
 
-    Index{table:Box<Self>,idx:Box<Self>},
-    Call{function:Box<Self>,args:Vec<Self>},
-    MethodCall{table:Box<Self>,name:Box<str>,args:Vec<Self>},
+    Index{table:Box<Spanned<Self>>,idx:Box<Spanned<Self>>},
+    Call{function:Box<Spanned<Self>>,args:Vec<Spanned<Self>>},
+    MethodCall{table:Box<Spanned<Self>>,name:Box<str>,args:Vec<Spanned<Self>>},
 }
 
 #[derive(Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum TableLiteralIdx {
     BoolLiteral(bool),
-    IntLiteral(i32),
+    IntLiteral(i64),
     FloatLiteral(f32),
     StrLiteral(Box<str>), 
 }
 
 #[derive(Clone)]
 pub struct TableLiteral {
-    pub arr:Vec<Expr>,
-    pub map:Vec<(TableLiteralIdx,Expr)>,
+    pub arr:Vec<Spanned<Expr>>,
+    pub map:Vec<(TableLiteralIdx,Spanned<Expr>)>,
 }
 
 #[derive(Clone)]
 pub struct InlineFunction {
     pub args:Vec<Box<str>>,
+    pub var_args:bool,
     pub block:Block,
 }
 
 
 impl Expr {
-    pub fn parse(tokens:&[Token]) -> Result<Expr> {
-        parse_rec(tokens)
+    /// Parses `tokens` as a single expression, all tokens have to be consumed.
+    pub fn parse(tokens:&[Spanned<Token>]) -> Result<Spanned<Expr>> {
+        if tokens.is_empty() {
+            return Err(Error::Parser(ParserErr::EmptyExpr,Token::span_of(tokens)));
+        }
+        let mut parser = Parser::new(tokens);
+        let expr = parser.parse_expr()?;
+        if !parser.is_at_end() {
+            return Err(parser.unexpected("operator"));
+        }
+        parser.into_result(expr)
     }
 
     pub fn display_tree(&self,depth:u32) {
@@ -87,21 +100,22 @@ impl Expr {
             print!("  ");
         }
         match self {
-            Expr::Ident(name) => print!("var({})\n",name),
-
-            Expr::NilLiteral               => print!("nil\n"),
-            Expr::BoolLiteral(x)    => print!("bool({:?})\n",x),
-            Expr::IntLiteral(x)      => print!("int({:?})\n",x),
-            Expr::FloatLiteral(x)    => print!("float({:?})\n",x),
-            Expr::StrLiteral(x) => print!("string({:?})\n",x),
+            Expr::Ident(name) => println!("var({})",name),
+            Expr::VarArgs => println!("..."),
+
+            Expr::NilLiteral               => println!("nil"),
+            Expr::BoolLiteral(x)    => println!("bool({:?})",x),
+            Expr::IntLiteral(x)      => println!("int({:?})",x),
+            Expr::FloatLiteral(x)    => println!("float({:?})",x),
+            Expr::StrLiteral(x) => println!("string({:?})",x),
             Expr::TableLiteral(t) => {
-                print!("table:\n");
+                println!("table:");
 
                 for v in &t.arr {
                     for _ in 0..depth+1 {
                         print!("  ");
                     }
-                    v.display_tree(depth+2);
+                    v.node.display_tree(depth+2);
                 }
 
                 for (k,v) in &t.map {
@@ -109,13 +123,13 @@ impl Expr {
                         print!("  ");
                     }
                     match k {
-                        TableLiteralIdx::BoolLiteral(x)    => print!("bool({:?}) =\n",x),
-                        TableLiteralIdx::IntLiteral(x)      => print!("int({:?}) =\n",x),
-                        TableLiteralIdx::FloatLiteral(x)    => print!("float({:?}) =\n",x),
-                        TableLiteralIdx::StrLiteral(x) => print!("str({:?}) =\n",x),
+                        TableLiteralIdx::BoolLiteral(x)    => println!("bool({:?}) =",x),
+                        TableLiteralIdx::IntLiteral(x)      => println!("int({:?}) =",x),
+                        TableLiteralIdx::FloatLiteral(x)    => println!("float({:?}) =",x),
+                        TableLiteralIdx::StrLiteral(x) => println!("str({:?}) =",x),
                     }
 
-                    v.display_tree(depth+2);
+                    v.node.display_tree(depth+2);
                 }
             }
 
@@ -127,38 +141,38 @@ impl Expr {
                         print!(",");
                     }
                 }
-                print!(")\n");
+                println!(")");
             },
 
             Expr::Binary { op, lhs, rhs } => {
-                print!("{:?}:\n",op);
-                lhs.display_tree(depth+1);
-                rhs.display_tree(depth+1);
+                println!("{:?}:",op);
+                lhs.node.display_tree(depth+1);
+                rhs.node.display_tree(depth+1);
             }
             Expr::Unary { op, val } => {
-                print!("{:?}:\n",op);
-                val.display_tree(depth+1);
+                println!("{:?}:",op);
+                val.node.display_tree(depth+1);
             }
 
             Expr::Index { table, idx } => {
-                print!("Index:\n");
-                table.display_tree(depth+1);
-                idx.display_tree(depth+1);
+                println!("Index:");
+                table.node.display_tree(depth+1);
+                idx.node.display_tree(depth+1);
             }
 
             Expr::Call { function, args } => {
-                print!("Call:\n");
-                function.display_tree(depth+1);
+                println!("Call:");
+                function.node.display_tree(depth+1);
                 for arg in args {
-                    arg.display_tree(depth+1);
+                    arg.node.display_tree(depth+1);
                 }
             }
 
             Expr::MethodCall { table, name, args } => {
-                print!("Metod({}):\n",name);
-                table.display_tree(depth+1);
+                println!("Metod({}):",name);
+                table.node.display_tree(depth+1);
                 for arg in args {
-                    arg.display_tree(depth+1);
+                    arg.node.display_tree(depth+1);
                 }
             }
         }
@@ -166,209 +180,208 @@ impl Expr {
 }
 
 
-fn parse_rec(tokens:&[Token]) -> Result<Expr> {
-    if tokens.len() == 1 {
-        return Ok(match &tokens[0] {
-            Token::Nil => Expr::NilLiteral,
-            Token::BoolLiteral(x)    => Expr::BoolLiteral(*x),
-            Token::IntLiteral(x)      => Expr::IntLiteral(*x),
-            Token::FloatLiteral(x)    => Expr::FloatLiteral(*x),
-            Token::StrLiteral(x) => Expr::StrLiteral(x.clone()),
-            Token::Ident(x)      => Expr::Ident(x.clone()),
-            _ => panic!("invalid token {:?}",tokens[0])
-        });
+/// Binding power of the binary operators, higher binds tighter.
+///
+/// | prec | operators                    | assoc |
+/// |------|------------------------------|-------|
+/// | 1    | `or`                         | left  |
+/// | 2    | `and`                        | left  |
+/// | 3    | `<` `>` `<=` `>=` `==` `!=`  | left  |
+/// | 4    | `\|`                         | left  |
+/// | 5    | `~`                          | left  |
+/// | 6    | `&`                          | left  |
+/// | 7    | `<<` `>>`                    | left  |
+/// | 8    | `..`                         | left  |
+/// | 9    | `+` `-`                      | left  |
+/// | 10   | `*` `/` `//` `%`             | left  |
+/// | 11   | unary `-` `!` `not` `#`      |       |
+/// | 12   | `^`                          | right |
+///
+/// Calls, indexing and method calls bind tighter than any operator.
+impl Op {
+    pub fn precedence(self) -> u32 {
+        match self {
+            Op::BoolOr => 1,
+            Op::BoolAnd => 2,
+            Op::Less|Op::LessEq|Op::Eq|Op::NotEq|Op::Greater|Op::GreaterEq => 3,
+            Op::Or => 4,
+            Op::Xor => 5,
+            Op::And => 6,
+            Op::Shl|Op::Shr => 7,
+            Op::Concat => 8,
+            Op::Add|Op::Sub => 9,
+            Op::Mul|Op::Div|Op::IDiv|Op::Mod => 10,
+            Op::Pow => 12,
+        }
     }
 
-    if let Some(i) = find_highest_order_op(tokens) {
-        let op = tokens[i].op().unwrap();
-        return Ok(Expr::Binary{ 
-            op,
-            lhs: Box::new(parse_rec(&tokens[0..i])?),
-            rhs: Box::new(parse_rec(&tokens[(i+1)..])?),
-        });
+    pub fn is_right_assoc(self) -> bool {
+        matches!(self,Op::Pow)
     }
+}
 
-    if let Some(op) = tokens[0].unary_op() {
-        return Ok(Expr::Unary{
-            op,
-            val: Box::new(parse_rec(&tokens[1..])?)
-        });
-    }
+const UNARY_PRECEDENCE:u32 = 11;
 
-    match tokens[0] {
-        Token::Function => {
-            let args_close_bracket = Token::find(tokens, &Token::RoundC).unwrap();
-            let block_close_bracket = Token::find_matching_bracket(tokens,args_close_bracket+1);
-
-            if block_close_bracket == Some(tokens.len()-1) {
-                let mut arg_iter = tokens[2..args_close_bracket].into_iter();
-                let mut args:Vec<Box<str>> = vec![];
-                loop {
-                    let arg_name = match arg_iter.next() {
-                        Some(token) => match token {
-                            Token::Ident(name) => name,
-                            _ => panic!("invalid arg {:?}",token)
-                        },
-
-                        None => break
-                    };
-                    args.push(arg_name.clone());
-
-                    match arg_iter.next() {
-                        Some(token) => match token {
-                            Token::Comma => {},
-                            _ => panic!("expected comma, got {:?}",token)
-                        },
-
-                        None => break
-                    }
-                }
 
-                let block = ast_gen::parse_block(&tokens[args_close_bracket+2..block_close_bracket.unwrap()]).unwrap();
+impl Parser<'_> {
+    pub fn parse_expr(&mut self) -> Result<Spanned<Expr>> {
+        self.parse_binary(0)
+    }
 
-                return Ok(Expr::Function(InlineFunction{
-                    args,
-                    block,
-                }));
+    fn parse_binary(&mut self,min_prec:u32) -> Result<Spanned<Expr>> {
+        let mut lhs = self.parse_unary()?;
+
+        while let Some(op) = self.peek().and_then(Token::op) {
+            let prec = op.precedence();
+            if prec < min_prec {
+                break;
             }
+            self.bump();
+
+            let rhs = self.parse_binary(if op.is_right_assoc() {prec} else {prec+1})?;
+            let span = lhs.span.to(rhs.span);
+            lhs = Spanned::new(Expr::Binary{ op, lhs:Box::new(lhs), rhs:Box::new(rhs) },span);
         }
-        _ => {}
+
+        Ok(lhs)
     }
 
-    match tokens.last().unwrap() {
-        Token::SquareC => {
-            let open_idx = Token::find_matching_bracket_rev(tokens).unwrap();
-            return Ok(Expr::Index{
-                table: Box::new(parse_rec(&tokens[0..open_idx])?),
-                idx: Box::new(parse_rec(&tokens[(open_idx+1)..tokens.len()-1])?), 
-            });
+    fn parse_unary(&mut self) -> Result<Spanned<Expr>> {
+        if let Some(op) = self.peek().and_then(Token::unary_op) {
+            let start = self.bump().unwrap().span;
+            let val = self.parse_binary(UNARY_PRECEDENCE)?;
+            let span = start.to(val.span);
+            return Ok(Spanned::new(Expr::Unary{ op, val:Box::new(val) },span));
         }
+        self.parse_postfix()
+    }
 
-        Token::RoundC => {
-            let open_idx = Token::find_matching_bracket_rev(tokens).unwrap();
-            if open_idx == 0 {
-                return parse_rec(&tokens[1..tokens.len()-1]);
-            } else {
-                let args = parse_args(&tokens[(open_idx+1)..tokens.len()-1])?;
-                if open_idx != 1 {
-                    if tokens[open_idx-2] == Token::Colon {
-                        let name = match &tokens[open_idx-1] {
-                            Token::Ident(x) => x.clone(),
-                            _ => panic!("{:?}",tokens[open_idx-1])
-                        };
-                        return Ok(Expr::MethodCall{
-                            table: Box::new(parse_rec(&tokens[0..open_idx-2])?),
-                            name,
-                            args, 
-                        });
-                    }
+    fn parse_postfix(&mut self) -> Result<Spanned<Expr>> {
+        let mut expr = self.parse_primary()?;
+
+        loop {
+            let start = expr.span;
+            let node = match self.peek() {
+                Some(Token::SquareO) => {
+                    let open_span = self.bump().unwrap().span;
+                    let idx = self.parse_expr()?;
+                    self.close_bracket(&Token::SquareO, open_span, "`]`")?;
+                    Expr::Index{ table:Box::new(expr), idx:Box::new(idx) }
                 }
 
-                return Ok(Expr::Call{
-                    function: Box::new(parse_rec(&tokens[0..open_idx])?),
-                    args,
-                });
-            }
-        }
+                Some(Token::Dot) => {
+                    self.bump();
+                    let name = self.parse_ident("field name")?;
+                    Expr::Index{ table:Box::new(expr), idx:Box::new(name.map(Expr::StrLiteral)) }
+                }
 
-        Token::CurlyC => {
-            assert_eq!(Token::find_matching_bracket_rev(tokens),Some(0));
-            return Ok(Expr::TableLiteral(parse_table_literal(&tokens[1..tokens.len()-1])?));
-        }
+                Some(Token::RoundO) => {
+                    let args = self.parse_args()?;
+                    Expr::Call{ function:Box::new(expr), args }
+                }
 
-        Token::Ident(name) => {
-            assert_eq!(tokens[tokens.len()-2],Token::Dot);
-            return Ok(Expr::Index{
-                table: Box::new(parse_rec(&tokens[0..(tokens.len()-2)])?),
-                idx: Box::new(Expr::StrLiteral(name.clone())),
-            });
+                Some(Token::Colon) => {
+                    self.bump();
+                    let name = self.parse_ident("method name")?.node;
+                    let args = self.parse_args()?;
+                    Expr::MethodCall{ table:Box::new(expr), name, args }
+                }
+
+                _ => return Ok(expr),
+            };
+            expr = Spanned::new(node,start.to(self.prev_span()));
         }
-        _ => {}
     }
-    panic!("{:?}",tokens);
-}
 
+    fn parse_primary(&mut self) -> Result<Spanned<Expr>> {
+        let Some(token) = self.peek() else {
+            return Err(self.unexpected("expression"));
+        };
+        let start = self.peek_span();
 
-fn parse_args(tokens:&[Token]) -> Result<Vec<Expr>> {
-    if tokens.is_empty() {
-        return Ok(vec![]);
-    }
+        let node = match token {
+            Token::Nil => Expr::NilLiteral,
+            Token::BoolLiteral(x)  => Expr::BoolLiteral(*x),
+            Token::IntLiteral(x)   => Expr::IntLiteral(*x),
+            Token::FloatLiteral(x) => Expr::FloatLiteral(*x),
+            Token::StrLiteral(x)   => Expr::StrLiteral(x.clone()),
+            Token::Ident(x)        => Expr::Ident(x.clone()),
+            Token::VarArgs         => Expr::VarArgs,
+
+            Token::RoundO => {
+                self.bump();
+                let expr = self.parse_expr()?;
+                self.close_bracket(&Token::RoundO, start, "`)`")?;
+                return Ok(Spanned::new(expr.node,start.to(self.prev_span())));
+            }
 
-    if let Some(comma_idx) = Token::find_outside_of_brackets(tokens, &Token::Comma) {
-        let arg = Expr::parse(&tokens[..comma_idx])?;
-        let mut args = parse_args(&tokens[comma_idx+1..])?;
-        args.insert(0, arg);
-        Ok(args)
-    } else {
-        Ok(vec![Expr::parse(&tokens[..])?])
-    }
-}
+            Token::CurlyO => {
+                let table = self.parse_table_literal()?;
+                return Ok(Spanned::new(Expr::TableLiteral(table),start.to(self.prev_span())));
+            }
 
-fn parse_table_literal(tokens:&[Token]) -> Result<TableLiteral> {
-    let mut table = TableLiteral{arr:vec![],map:vec![]};
-    parse_table_literal_rec(tokens, &mut table)?;
-    Ok(table)
-}
+            Token::Function => {
+                self.bump();
+                let (args,var_args) = self.parse_params()?;
+                let block = self.parse_braced_block()?;
+                return Ok(Spanned::new(Expr::Function(InlineFunction{ args, var_args, block }),start.to(self.prev_span())));
+            }
 
-fn parse_table_literal_rec(tokens:&[Token], table:&mut TableLiteral) -> Result<()> {
-    if tokens.is_empty() {
-        return Ok(());
+            _ => return Err(self.unexpected("expression")),
+        };
+
+        self.bump();
+        Ok(Spanned::new(node,start))
     }
 
-    if let Some(comma_idx) = Token::find_outside_of_brackets(tokens, &Token::Comma) {
-        parse_table_literal_element(&tokens[..comma_idx],table)?;
-        parse_table_literal_rec(&tokens[comma_idx+1..],table)?;
-    } else {
-        parse_table_literal_element(tokens, table)?;
+    fn parse_args(&mut self) -> Result<Vec<Spanned<Expr>>> {
+        let open_span = self.expect(&Token::RoundO, "`(`")?;
+        let mut args = vec![];
+        if self.eat(&Token::RoundC) {
+            return Ok(args);
+        }
+
+        loop {
+            args.push(self.parse_expr()?);
+            if !self.eat(&Token::Comma) {
+                self.close_bracket(&Token::RoundO, open_span, "`,` or `)`")?;
+                return Ok(args);
+            }
+        }
     }
 
-    Ok(())
-}
+    /// Trailing commas are allowed.
+    fn parse_table_literal(&mut self) -> Result<TableLiteral> {
+        let open_span = self.expect(&Token::CurlyO, "`{`")?;
+        let mut table = TableLiteral{arr:vec![],map:vec![]};
 
-fn parse_table_literal_element(tokens:&[Token],table:&mut TableLiteral) -> Result<()> {
-    let has_equal = if tokens.len() > 2 {
-        tokens[1] == Token::Assing
-    } else {
-        false
-    };
-
-    if has_equal {
-        let idx = match &tokens[0] {
-            Token::BoolLiteral(x) => TableLiteralIdx::BoolLiteral(*x), 
-            Token::IntLiteral(x)   => TableLiteralIdx::IntLiteral(*x), 
-            Token::FloatLiteral(x) => TableLiteralIdx::FloatLiteral(*x), 
-            Token::StrLiteral(x)|Token::Ident(x) => TableLiteralIdx::StrLiteral(x.clone()), 
-            _ => panic!("invalid idx {:?}",tokens[0])
-        };
-        let val = Expr::parse(&tokens[2..])?;
-        table.map.push((idx,val));
-    } else {
-        table.arr.push(Expr::parse(tokens)?);
-    };
+        while !self.eat(&Token::CurlyC) {
+            if self.is_at_end() {
+                return Err(Error::Parser(ParserErr::UnmatchedBracket(Token::CurlyO),open_span));
+            }
 
-    Ok(())
-}
+            if self.peek_nth(1) == Some(&Token::Assing) {
+                let key = self.bump().unwrap();
+                let idx = match &key.node {
+                    Token::BoolLiteral(x) => TableLiteralIdx::BoolLiteral(*x), 
+                    Token::IntLiteral(x)   => TableLiteralIdx::IntLiteral(*x), 
+                    Token::FloatLiteral(x) => TableLiteralIdx::FloatLiteral(*x), 
+                    Token::StrLiteral(x)|Token::Ident(x) => TableLiteralIdx::StrLiteral(x.clone()), 
+                    _ => return Err(Error::Parser(ParserErr::InvalidTableKey(key.node.clone()),key.span))
+                };
+                self.bump();
+                table.map.push((idx,self.parse_expr()?));
+            } else {
+                table.arr.push(self.parse_expr()?);
+            }
 
-fn find_highest_order_op(tokens:&[Token]) -> Option<usize> {
-    let mut depth = 0;
-    let mut highest_prio = 0;
-    let mut highest_idx = usize::MAX;
-
-    for (i,token) in tokens.iter().enumerate() {
-        depth += token.brack_depth();
-        if depth == 0 {
-            let prio = token.op_priority();
-            if prio >= highest_prio {
-                highest_prio = prio;
-                highest_idx = i;
+            if !self.eat(&Token::Comma) {
+                self.close_bracket(&Token::CurlyO, open_span, "`,` or `}`")?;
+                break;
             }
         }
-    }
 
-    if highest_prio != 0 {
-        Some(highest_idx)
-    } else {
-        None
+        Ok(table)
     }
 }
 
@@ -379,32 +392,17 @@ impl Token {
     }
 
     pub fn is_valid_start_of_statement(&self) -> bool {
-        match self {
+        matches!(self,
             Token::Function|Token::Ident(_)|Token::Local|Token::If|
-            Token::While|Token::Break|Token::Return|Token::For => true,
-            _ => false,
-        }
+            Token::While|Token::Break|Token::Return|Token::For
+        )
     }
 
     pub fn is_valid_end_of_expr(&self) -> bool {
-        match self {
+        matches!(self,
             Token::RoundC|Token::CurlyC|Token::SquareC|
-            Token::Ident(_)|Token::Nil|Token::BoolLiteral(_)|Token::IntLiteral(_)|Token::FloatLiteral(_)|Token::StrLiteral(_) => true,
-            _ => false
-        }
-    }
-
-    pub fn op_priority(&self) -> u32 {
-        match self {
-            Token::Add|Token::Sub => 9,
-            Token::Mul|Token::Div|Token::IDiv|Token::Mod =>8,
-            Token::Pow => 7,
-            Token::And|Token::Or|Token::Xor => 6,
-            Token::Less|Token::LessEq|Token::Eq|Token::NotEq|Token::Greater|Token::GreaterEq => 5,
-            Token::BoolAnd|Token::BoolOr => 4,
-            Token::Concat => 3,
-            _ => 0, 
-        }
+            Token::Ident(_)|Token::VarArgs|Token::Nil|Token::BoolLiteral(_)|Token::IntLiteral(_)|Token::FloatLiteral(_)|Token::StrLiteral(_)
+        )
     }
 
     pub fn op(&self) -> Option<Op> {
@@ -420,6 +418,8 @@ impl Token {
             Token::And       => Some(Op::And),
             Token::Or        => Some(Op::Or),
             Token::Xor       => Some(Op::Xor),
+            Token::Shl       => Some(Op::Shl),
+            Token::Shr       => Some(Op::Shr),
             Token::Less      => Some(Op::Less),
             Token::LessEq    => Some(Op::LessEq),
             Token::Eq        => Some(Op::Eq),
@@ -443,20 +443,80 @@ impl Token {
     }
 }
 
+#[cfg(test)]
+fn to_sexpr(expr:&Expr) -> String {
+    let list = |args:&[Spanned<Expr>]| args.iter().map(|x| to_sexpr(&x.node)).collect::<Vec<_>>().join(" ");
+    match expr {
+        Expr::Ident(x) => x.to_string(),
+        Expr::IntLiteral(x) => x.to_string(),
+        Expr::Binary { op, lhs, rhs } => format!("({:?} {} {})",op,to_sexpr(&lhs.node),to_sexpr(&rhs.node)),
+        Expr::Unary { op, val } => format!("({:?} {})",op,to_sexpr(&val.node)),
+        Expr::Index { table, idx } => format!("(Index {} {})",to_sexpr(&table.node),to_sexpr(&idx.node)),
+        Expr::StrLiteral(x) => format!("{:?}",x),
+        Expr::Call { function, args } => format!("(Call {} [{}])",to_sexpr(&function.node),list(args)),
+        Expr::MethodCall { table, name, args } => format!("(Method {} {} [{}])",to_sexpr(&table.node),name,list(args)),
+        _ => "?".to_string(),
+    }
+}
+
 #[test]
-fn test_lowest_order_op() {
+fn test_precedence() {
     use super::tokenizer;
-    let tokens = tokenizer::parse("map(function(x) x+1 end, x[i+2+3.31], {x=32^1.5})").unwrap();
-    assert_eq!(find_highest_order_op(&tokens),None);
+    let parse = |src:&str| to_sexpr(&Expr::parse(&tokenizer::parse(src).unwrap()).unwrap().node);
+
+    assert_eq!(parse("a - b - c"),"(Sub (Sub a b) c)");
+    assert_eq!(parse("a ^ b ^ c"),"(Pow a (Pow b c))");
+    assert_eq!(parse("a + b * c"),"(Add a (Mul b c))");
+    assert_eq!(parse("(a + b) * c"),"(Mul (Add a b) c)");
+    assert_eq!(parse("a + 1 == b and c or d"),"(BoolOr (BoolAnd (Eq (Add a 1) b) c) d)");
+    assert_eq!(parse("a .. b + c"),"(Concat a (Add b c))");
+    assert_eq!(parse("a & b | c ~ d"),"(Or (And a b) (Xor c d))");
+    assert_eq!(parse("#t + n"),"(Add (Len t) n)");
+    assert_eq!(parse("not a == b"),"(Eq (BoolNot a) b)");
+    assert_eq!(parse("!a ^ b"),"(Not (Pow a b))");
+    assert_eq!(parse("a * f(x, y + 1)[2]"),"(Mul a (Index (Call f [x (Add y 1)]) 2))");
+    assert_eq!(parse("1 + t.x:get(2).y"),"(Add 1 (Index (Method (Index t \"x\") get [2]) \"y\"))");
+    assert_eq!(parse("#t.x[1] - n"),"(Sub (Len (Index (Index t \"x\") 1)) n)");
+    assert_eq!(parse("-a ^ b"),"(Neg (Pow a b))");
+    assert_eq!(parse("a - -b * c"),"(Sub a (Mul (Neg b) c))");
+    assert_eq!(parse("f(x) -1"),"(Sub (Call f [x]) 1)");
+    assert_eq!(parse("a // b != c >> 1"),"(NotEq (IDiv a b) (Shr c 1))");
+}
 
-    let tokens = tokenizer::parse("(32 and 3)*2^2+a+a[a^2]").unwrap();
-    assert_eq!(find_highest_order_op(&tokens),Some(11));
-    assert_eq!(tokens[find_highest_order_op(&tokens).unwrap()],Token::Add);
+#[test]
+fn test_parse_errors() {
+    use super::tokenizer;
+    let parse = |src:&str| Expr::parse(&tokenizer::parse(src).unwrap());
+
+    assert!(matches!(parse("a +"),Err(Error::Parser(ParserErr::UnexpectedEnd{..},_))));
+    assert!(matches!(parse("a b"),Err(Error::Parser(ParserErr::UnexpectedToken{ expected:"operator", .. },_))));
+    assert!(matches!(parse("f(a b)"),Err(Error::Parser(ParserErr::UnexpectedToken{ expected:"`,` or `)`", .. },_))));
+    assert!(matches!(parse("(a"),Err(Error::Parser(ParserErr::UnmatchedBracket(_),_))));
+    assert!(matches!(parse("{1.5 = x, + = y}"),Err(Error::Parser(ParserErr::InvalidTableKey(Token::Add),_))));
+    assert!(matches!(parse("()"),Err(Error::Parser(ParserErr::UnexpectedToken{ found:Token::RoundC, .. },_))));
+}
+
+#[test]
+fn test_spans() {
+    use super::tokenizer;
+    let src = "x + (y * 2) + f(1)[2]";
+    let expr = Expr::parse(&tokenizer::parse(src).unwrap()).unwrap();
+    assert_eq!(expr.span.start..expr.span.end,0..src.len());
+    match expr.node {
+        Expr::Binary { lhs, rhs, .. } => {
+            assert_eq!(&src[rhs.span.start..rhs.span.end],"f(1)[2]");
+            match lhs.node {
+                Expr::Binary { rhs, .. } => assert_eq!(&src[rhs.span.start..rhs.span.end],"(y * 2)"),
+                _ => panic!(),
+            }
+        }
+        _ => panic!(),
+    }
 }
 
 #[test]
 fn test_parse() {
     use super::tokenizer;
-    let tokens = tokenizer::parse("{1,2,x=function(a,x) {1+a+x} }[i]({},f(32,true))").unwrap();
-    Expr::parse(&tokens).unwrap().display_tree(0);
+    let tokens = tokenizer::parse("{1,2,x=function(a,x) {return 1+a+x;} }[i]({},f(32,true))").unwrap();
+    Expr::parse(&tokens).unwrap().node.display_tree(0);
 }
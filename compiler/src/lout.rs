@@ -0,0 +1,206 @@
+use crate::err::{Error, FormatErr, Result};
+
+pub const MAGIC:[u8;4] = *b"MUNA";
+pub const FORMAT_VERSION:u16 = 2;
+pub const HEADER_LEN:usize = 16;
+const SECTION_ENTRY_LEN:usize = 12;
+
+const NATIVE_ENDIAN:u8 = if cfg!(target_endian = "little") {0} else {1};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SectionKind {
+    /// Name count as `u32`, followed by the nul terminated names.
+    Names  = 0,
+    /// The prototype of the main function.
+    Main   = 1,
+    /// Prototype count as `u32`, followed by the prototypes.
+    Protos = 2,
+}
+
+impl SectionKind {
+    fn from_u32(x:u32) -> Option<Self> {
+        match x {
+            0 => Some(SectionKind::Names),
+            1 => Some(SectionKind::Main),
+            2 => Some(SectionKind::Protos),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SectionKind::Names => "names",
+            SectionKind::Main => "main",
+            SectionKind::Protos => "prototypes",
+        }
+    }
+}
+
+/// Puts the header and the section table in front of the sections.
+///
+/// | offset | size | content                                            |
+/// |--------|------|----------------------------------------------------|
+/// | 0      | 4    | `MAGIC`                                            |
+/// | 4      | 2    | `FORMAT_VERSION`                                   |
+/// | 6      | 1    | endianness of the code words, 0 little and 1 big   |
+/// | 7      | 1    | section count                                      |
+/// | 8      | 4    | CRC-32 of everything after the header              |
+/// | 12     | 4    | length of everything after the header              |
+///
+/// Every entry of the section table is its kind, offset from the start of the file and length, each a `u32`.
+/// All header fields are little endian, so every offset and length has to fit into a `u32`.
+pub fn encode(sections:&[(SectionKind,Vec<u8>)]) -> Result<Vec<u8>> {
+    let table_len = sections.len()*SECTION_ENTRY_LEN;
+    let payload_len = table_len + sections.iter().map(|x| x.1.len()).sum::<usize>();
+    if HEADER_LEN + payload_len > u32::MAX as usize {
+        return Err(Error::Format(FormatErr::TooLarge));
+    }
+
+    let mut out = Vec::with_capacity(HEADER_LEN + payload_len);
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    out.push(NATIVE_ENDIAN);
+    out.push(sections.len() as u8);
+    out.extend_from_slice(&[0;4]);
+    out.extend_from_slice(&(payload_len as u32).to_le_bytes());
+
+    let mut offset = HEADER_LEN + table_len;
+    for (kind,section) in sections {
+        out.extend_from_slice(&(*kind as u32).to_le_bytes());
+        out.extend_from_slice(&(offset as u32).to_le_bytes());
+        out.extend_from_slice(&(section.len() as u32).to_le_bytes());
+        offset += section.len();
+    }
+    for (_,section) in sections {
+        out.extend_from_slice(section);
+    }
+
+    let crc = crc32fast::hash(&out[HEADER_LEN..]);
+    out[8..12].copy_from_slice(&crc.to_le_bytes());
+    Ok(out)
+}
+
+/// A validated `.lout` file.
+pub struct LoutFile<'a> {
+    pub version:u16,
+    sections:Vec<(SectionKind,&'a [u8])>,
+}
+
+impl<'a> LoutFile<'a> {
+    /// Checks the header and the checksum, and splits the file into its sections.
+    pub fn read(bytes:&'a [u8]) -> Result<Self> {
+        let err = |err| Err(Error::Format(err));
+
+        if bytes.len() < MAGIC.len() || bytes[..4] != MAGIC {
+            return err(FormatErr::BadMagic);
+        }
+        if bytes.len() < HEADER_LEN {
+            return err(FormatErr::Truncated);
+        }
+
+        let version = u16::from_le_bytes([bytes[4],bytes[5]]);
+        if version != FORMAT_VERSION {
+            return err(FormatErr::UnsupportedVersion(version));
+        }
+        if bytes[6] != NATIVE_ENDIAN {
+            return err(FormatErr::WrongEndianness);
+        }
+
+        let section_count = bytes[7] as usize;
+        let crc = read_u32(bytes, 8);
+        let payload_len = read_u32(bytes, 12) as usize;
+        let Some(payload) = bytes.get(HEADER_LEN..HEADER_LEN+payload_len) else {
+            return err(FormatErr::Truncated);
+        };
+        if bytes.len() != HEADER_LEN+payload_len {
+            return err(FormatErr::TrailingBytes);
+        }
+
+        let found = crc32fast::hash(payload);
+        if found != crc {
+            return err(FormatErr::ChecksumMismatch{ expected:crc, found });
+        }
+
+        let table_end = HEADER_LEN + section_count*SECTION_ENTRY_LEN;
+        if table_end > bytes.len() {
+            return err(FormatErr::Truncated);
+        }
+
+        let mut sections = Vec::with_capacity(section_count);
+        for entry in (HEADER_LEN..table_end).step_by(SECTION_ENTRY_LEN) {
+            let kind = read_u32(bytes, entry);
+            let offset = read_u32(bytes, entry+4) as usize;
+            let len = read_u32(bytes, entry+8) as usize;
+
+            let Some(kind) = SectionKind::from_u32(kind) else {
+                return err(FormatErr::UnknownSection(kind));
+            };
+            if offset < table_end {
+                return err(FormatErr::BadSectionTable);
+            }
+            let Some(section) = offset.checked_add(len).and_then(|end| bytes.get(offset..end)) else {
+                return err(FormatErr::Truncated);
+            };
+            sections.push((kind,section));
+        }
+
+        Ok(Self { version, sections })
+    }
+
+    pub fn section(&self,kind:SectionKind) -> Result<&'a [u8]> {
+        self.sections.iter()
+            .find(|x| x.0 == kind)
+            .map(|x| x.1)
+            .ok_or(Error::Format(FormatErr::MissingSection(kind.name())))
+    }
+
+    pub fn names(&self) -> Result<Vec<&'a str>> {
+        let section = self.section(SectionKind::Names)?;
+        let Some(count) = section.get(..4).map(|x| read_u32(x, 0)) else {
+            return Err(Error::Format(FormatErr::Truncated));
+        };
+
+        let mut names = section[4..].split(|x| *x == 0);
+        (0..count).map(|_| {
+            let name = names.next().ok_or(Error::Format(FormatErr::Truncated))?;
+            std::str::from_utf8(name).map_err(|_| Error::Format(FormatErr::InvalidName))
+        }).collect()
+    }
+}
+
+fn read_u32(bytes:&[u8],at:usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at+4].try_into().unwrap())
+}
+
+
+#[test]
+fn round_trip_test() {
+    let bytes = encode(&[(SectionKind::Names,vec![1,0,0,0,b'x',0]),(SectionKind::Main,vec![1,2,3])]).unwrap();
+    let file = LoutFile::read(&bytes).unwrap();
+    assert_eq!(file.version,FORMAT_VERSION);
+    assert_eq!(file.names().unwrap(),["x"]);
+    assert_eq!(file.section(SectionKind::Main).unwrap(),[1,2,3]);
+    assert!(matches!(file.section(SectionKind::Protos),Err(Error::Format(FormatErr::MissingSection("prototypes")))));
+}
+
+#[test]
+fn reject_test() {
+    let bytes = encode(&[(SectionKind::Main,vec![1,2,3])]).unwrap();
+    let read = |f:&dyn Fn(&mut Vec<u8>)| {
+        let mut bytes = bytes.clone();
+        f(&mut bytes);
+        match LoutFile::read(&bytes) {
+            Err(Error::Format(err)) => err,
+            _ => panic!(),
+        }
+    };
+
+    assert_eq!(read(&|x| x[0] = b'L'),FormatErr::BadMagic);
+    assert_eq!(read(&|x| x[4] = 9),FormatErr::UnsupportedVersion(9));
+    assert_eq!(read(&|x| x[6] ^= 1),FormatErr::WrongEndianness);
+    assert_eq!(read(&|x| _ = x.pop()),FormatErr::Truncated);
+    assert_eq!(read(&|x| x.push(0)),FormatErr::TrailingBytes);
+    assert_eq!(read(&|x| x.truncate(10)),FormatErr::Truncated);
+    assert!(matches!(read(&|x| *x.last_mut().unwrap() = 4),FormatErr::ChecksumMismatch { .. }));
+}
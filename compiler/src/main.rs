@@ -1,19 +1,92 @@
 #![allow(dead_code,unused_variables,unused_imports)]
 
+mod span;
 mod tokenizer;
 mod expr;
 mod ast_gen;
 mod utils;
 mod err;
+mod diagnostic;
 mod compiler;
 mod bytecode;
 mod asm;
+mod lout;
+mod disasm;
+mod masm;
 mod tests;
 
+use std::{path::Path, process::ExitCode};
+
+use crate::{asm::{ByteCodeVec, CompileCtx}, bytecode::ByteCode, compiler::FuncCtx, diagnostic::Diagnostic, disasm::Program};
 pub use crate::err::{Error,Result};
 
-fn main() {
-    
+fn compile(src:&str,out_path:&Path) -> std::result::Result<(),Vec<Error>> {
+    let tokens = tokenizer::parse(src).map_err(|err| vec![err])?;
+    let (block,errors) = ast_gen::parse(&tokens);
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut comp_ctx = CompileCtx::new();
+    let mut bytecode = ByteCodeVec::new();
+    FuncCtx::new(&[]).compile(&block, &mut comp_ctx, &mut bytecode, Some(ByteCode::Halt)).map_err(|err| vec![err])?;
+    comp_ctx.write_to_file(&bytecode, out_path).map_err(|err| vec![err])
+}
+
+fn assemble(src:&str,out_path:&Path) -> std::result::Result<(),Vec<Error>> {
+    let (bytecode,comp_ctx) = masm::assemble(src).map_err(|err| vec![err])?;
+    comp_ctx.write_to_file(&bytecode, out_path).map_err(|err| vec![err])
+}
+
+fn main() -> ExitCode {
+    let args:Vec<String> = std::env::args().collect();
+    let Some(src_path) = args.get(1) else {
+        eprintln!("usage: {} <source|file.masm> [output]", args[0]);
+        eprintln!("       {} --disasm <file.lout>", args[0]);
+        return ExitCode::FAILURE;
+    };
+
+    if src_path == "--disasm" {
+        return disassemble(args.get(2).map_or("", |x| x));
+    }
+    let out_path = args.get(2).map_or_else(|| Path::new(src_path).with_extension("lout"), |x| x.into());
+
+    let src = match std::fs::read_to_string(src_path) {
+        Ok(src) => src,
+        Err(err) => {
+            eprintln!("error: could not read `{}`: {}", src_path, err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = if src_path.ends_with(".masm") {
+        assemble(&src, &out_path)
+    } else {
+        compile(&src, &out_path)
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(errors) => {
+            let diagnostics:Vec<Diagnostic> = errors.iter().map(Diagnostic::from).collect();
+            eprint!("{}", diagnostic::render_all(&diagnostics, &src, src_path));
+            ExitCode::FAILURE
+        }
+    }
 }
 
+fn disassemble(path:&str) -> ExitCode {
+    let program = std::fs::read(path)
+        .map_err(Error::from)
+        .and_then(|bytes| Program::decode(&bytes));
 
+    match program {
+        Ok(program) => {
+            print!("{}", program.disassemble());
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            eprintln!("error: could not read `{}`: {}", path, err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+
+use crate::{asm::{ByteCodeVec, CompileCtx, FuncProto, LabelId, UpvalDesc}, bytecode::{ByteCode, CallArgs, VAR_COUNT, VAR_RET_COUNT}, err::{AsmErr, Error, Result}, span::Span};
+
+/// Every instruction with placeholder operands, to look them up by their mnemonic.
+const INSTRS:&[ByteCode] = &[
+    ByteCode::LoadNil, ByteCode::LoadTrue, ByteCode::LoadFalse, ByteCode::LoadInt(0), ByteCode::LoadFloat(0.0),
+    ByteCode::LoadStr(0), ByteCode::Load(0), ByteCode::Write(0), ByteCode::Pop, ByteCode::Dup,
+    ByteCode::Add, ByteCode::Sub, ByteCode::Mul, ByteCode::Div, ByteCode::IDiv, ByteCode::Pow, ByteCode::Mod, ByteCode::Concat,
+    ByteCode::And, ByteCode::Or, ByteCode::Xor, ByteCode::Shl, ByteCode::Shr, ByteCode::BoolAnd, ByteCode::BoolOr,
+    ByteCode::Less(false), ByteCode::LessEq(false), ByteCode::Eq(false),
+    ByteCode::Neg, ByteCode::Not, ByteCode::BoolNot, ByteCode::Len,
+    ByteCode::NewTable(0), ByteCode::Get, ByteCode::Set, ByteCode::SetPop, ByteCode::Push, ByteCode::PushAll,
+    ByteCode::GetMethod(0), ByteCode::Next(0),
+    ByteCode::Closure(0), ByteCode::Call(CallArgs{ arg_count:0, ret_count:0 }), ByteCode::Ret(0),
+    ByteCode::BindUpval(0), ByteCode::GetUpval(0), ByteCode::SetUpval(0), ByteCode::Close(0),
+    ByteCode::LoadVarArgs(0), ByteCode::GetGlobal(0), ByteCode::SetGlobal(0),
+    ByteCode::Jump(LabelId::nth(0)), ByteCode::JumpTrue(LabelId::nth(0)), ByteCode::JumpFalse(LabelId::nth(0)),
+    ByteCode::Halt,
+];
+
+/// Assembles `.masm` source into the main function and a `CompileCtx` with the names and prototypes,
+/// ready to be encoded like the output of the compiler.
+///
+/// ```text
+/// ; comments start with `;`
+/// .function add args=2        ; `var_args` makes it variadic
+/// .upval local 1              ; or `.upval upval 0`, in the order of the upvalues
+///     load 1
+///     load 2
+///     add
+///     ret 1
+/// .end
+///
+///     closure @add            ; everything outside of `.function` belongs to the main function
+///     load_str "a\tstring"    ; strings and names are added to the name table
+///     get_global print
+///     call var 1              ; `var` is `VAR_COUNT`
+/// loop:
+///     jump loop
+/// ```
+pub fn assemble(src:&str) -> Result<(ByteCodeVec,CompileCtx)> {
+    let lines = lines(src)?;
+
+    // Prototypes are numbered in the order they are declared, so closures can refer to later ones.
+    let mut functions = HashMap::new();
+    for line in &lines {
+        if let [directive,name,..] = &line[..] {
+            if directive.text == ".function" {
+                let idx = functions.len() as u32;
+                if functions.insert(name.text, idx).is_some() {
+                    return Err(Error::Asm(AsmErr::DuplicateFunction(name.text.into()),name.span));
+                }
+            }
+        }
+    }
+
+    let mut comp_ctx = CompileCtx::new();
+    let mut main = Func::new("(main)", 0, false);
+    let mut current:Option<(Func,Span)> = None;
+
+    for line in &lines {
+        let Some((first,operands)) = line.split_first() else {
+            continue;
+        };
+        let func = current.as_mut().map_or(&mut main, |x| &mut x.0);
+
+        match first.text {
+            ".function" => {
+                if current.is_some() {
+                    return Err(Error::Asm(AsmErr::NestedFunction,first.span));
+                }
+                current = Some((parse_function(first, operands)?,first.span));
+            }
+
+            ".upval" => {
+                let Some((func,_)) = &mut current else {
+                    return Err(Error::Asm(AsmErr::OutsideOfFunction(".upval"),first.span));
+                };
+                let mut operands = Operands{ tokens:operands, prev:first.span };
+                let kind = operands.next("`local` or `upval`")?;
+                let idx = operands.u32()?;
+                func.upvals.push(match kind.text {
+                    "local" => UpvalDesc::Local(idx),
+                    "upval" => UpvalDesc::Upval(idx),
+                    _ => return Err(Error::Asm(AsmErr::InvalidOperand{ expected:"`local` or `upval`" },kind.span)),
+                });
+                operands.end()?;
+            }
+
+            ".end" => {
+                let Some((func,_)) = current.take() else {
+                    return Err(Error::Asm(AsmErr::OutsideOfFunction(".end"),first.span));
+                };
+                Operands{ tokens:operands, prev:first.span }.end()?;
+                comp_ctx.add_proto(func.finish()?);
+            }
+
+            directive if directive.starts_with('.') => {
+                return Err(Error::Asm(AsmErr::UnknownDirective(directive.into()),first.span));
+            }
+
+            label if label.ends_with(':') => {
+                Operands{ tokens:operands, prev:first.span }.end()?;
+                let name = &label[..label.len()-1];
+                let id = func.label(name, first.span, &mut comp_ctx);
+                let label = func.labels.get_mut(name).unwrap();
+                if label.defined {
+                    return Err(Error::Asm(AsmErr::DuplicateLabel(name.into()),first.span));
+                }
+                label.defined = true;
+                func.code.add_label(id);
+            }
+
+            mnemonic => {
+                let Some(template) = INSTRS.iter().find(|x| x.mnemonic() == mnemonic) else {
+                    return Err(Error::Asm(AsmErr::UnknownInstruction(mnemonic.into()),first.span));
+                };
+                let mut operands = Operands{ tokens:operands, prev:first.span };
+                let instr = parse_instr(*template, &mut operands, func, &functions, &mut comp_ctx)?;
+                operands.end()?;
+                func.code.add_instr(instr);
+            }
+        }
+    }
+
+    if let Some((_,span)) = current {
+        return Err(Error::Asm(AsmErr::UnclosedFunction,span));
+    }
+    Ok((main.finish()?.code,comp_ctx))
+}
+
+fn parse_function(directive:&Token,operands:&[Token]) -> Result<Func> {
+    let mut operands = Operands{ tokens:operands, prev:directive.span };
+    let name = operands.next("function name")?.text;
+    let mut args = 0;
+    let mut var_args = false;
+
+    while let Some(attr) = operands.tokens.first() {
+        operands.tokens = &operands.tokens[1..];
+        match attr.text.split_once('=') {
+            Some(("args",x)) => args = x.parse().map_err(|_| Error::Asm(AsmErr::InvalidOperand{ expected:"argument count" },attr.span))?,
+            None if attr.text == "var_args" => var_args = true,
+            _ => return Err(Error::Asm(AsmErr::InvalidOperand{ expected:"`args=` or `var_args`" },attr.span)),
+        }
+    }
+    Ok(Func::new(name, args, var_args))
+}
+
+fn parse_instr(
+    template:ByteCode,
+    operands:&mut Operands,
+    func:&mut Func,
+    functions:&HashMap<&str,u32>,
+    comp_ctx:&mut CompileCtx,
+) -> Result<ByteCode> {
+    Ok(match template {
+        ByteCode::LoadInt(_) => ByteCode::LoadInt(operands.parse("integer")?),
+        ByteCode::LoadFloat(_) => ByteCode::LoadFloat(operands.parse("number")?),
+
+        ByteCode::Load(_) => ByteCode::Load(operands.u32()?),
+        ByteCode::Write(_) => ByteCode::Write(operands.u32()?),
+        ByteCode::NewTable(_) => ByteCode::NewTable(operands.u32()?),
+        ByteCode::Next(_) => ByteCode::Next(operands.u32()?),
+        ByteCode::BindUpval(_) => ByteCode::BindUpval(operands.u32()?),
+        ByteCode::GetUpval(_) => ByteCode::GetUpval(operands.u32()?),
+        ByteCode::SetUpval(_) => ByteCode::SetUpval(operands.u32()?),
+        ByteCode::Close(_) => ByteCode::Close(operands.u32()?),
+
+        ByteCode::LoadStr(_) => ByteCode::LoadStr(comp_ctx.get_idx_of_name(&operands.name()?)),
+        ByteCode::GetMethod(_) => ByteCode::GetMethod(comp_ctx.get_idx_of_name(&operands.name()?)),
+        ByteCode::GetGlobal(_) => ByteCode::GetGlobal(comp_ctx.get_idx_of_name(&operands.name()?)),
+        ByteCode::SetGlobal(_) => ByteCode::SetGlobal(comp_ctx.get_idx_of_name(&operands.name()?)),
+
+        ByteCode::Ret(_) => ByteCode::Ret(operands.count()?),
+        ByteCode::LoadVarArgs(_) => ByteCode::LoadVarArgs(operands.count()?),
+        ByteCode::Call(_) => {
+            let arg_count = operands.count()?;
+            let ret_count = match operands.next("result count")? {
+                x if x.text == "var" => VAR_RET_COUNT,
+                x => x.text.parse().ok()
+                    .filter(|x| *x != VAR_RET_COUNT)
+                    .ok_or(Error::Asm(AsmErr::InvalidOperand{ expected:"result count" },x.span))?,
+            };
+            ByteCode::Call(CallArgs{ arg_count, ret_count })
+        }
+
+        ByteCode::Less(_) => ByteCode::Less(operands.parse("`true` or `false`")?),
+        ByteCode::LessEq(_) => ByteCode::LessEq(operands.parse("`true` or `false`")?),
+        ByteCode::Eq(_) => ByteCode::Eq(operands.parse("`true` or `false`")?),
+
+        ByteCode::Jump(_) => ByteCode::Jump(operands.label(func, comp_ctx)?),
+        ByteCode::JumpTrue(_) => ByteCode::JumpTrue(operands.label(func, comp_ctx)?),
+        ByteCode::JumpFalse(_) => ByteCode::JumpFalse(operands.label(func, comp_ctx)?),
+
+        ByteCode::Closure(_) => {
+            let token = operands.next("`@function`")?;
+            let Some(name) = token.text.strip_prefix('@') else {
+                return Err(Error::Asm(AsmErr::InvalidOperand{ expected:"`@function`" },token.span));
+            };
+            let idx = functions.get(name).ok_or(Error::Asm(AsmErr::UnknownFunction(name.into()),token.span))?;
+            ByteCode::Closure(*idx)
+        }
+
+        instr => instr,
+    })
+}
+
+struct Label {
+    id:LabelId,
+    defined:bool,
+    first_use:Span,
+}
+
+struct Func {
+    name:Box<str>,
+    arg_count:u16,
+    var_args:bool,
+    upvals:Vec<UpvalDesc>,
+    code:ByteCodeVec,
+    labels:HashMap<Box<str>,Label>,
+}
+
+impl Func {
+    fn new(name:&str,arg_count:u16,var_args:bool) -> Self {
+        let mut code = ByteCodeVec::new();
+        code.set_height(arg_count as i32 + 1);
+        Self { name:name.into(), arg_count, var_args, upvals:vec![], code, labels:HashMap::new() }
+    }
+
+    fn label(&mut self,name:&str,span:Span,comp_ctx:&mut CompileCtx) -> LabelId {
+        self.labels.entry(name.into())
+            .or_insert_with(|| Label{ id:comp_ctx.new_label(), defined:false, first_use:span })
+            .id
+    }
+
+    fn finish(self) -> Result<FuncProto> {
+        if let Some((name,label)) = self.labels.iter().filter(|x| !x.1.defined).min_by_key(|x| x.1.first_use.start) {
+            return Err(Error::Asm(AsmErr::UnknownLabel(name.clone()),label.first_use));
+        }
+        Ok(FuncProto {
+            name:self.name,
+            max_stack:self.code.max_stack(),
+            code:self.code,
+            arg_count:self.arg_count,
+            var_args:self.var_args,
+            upvals:self.upvals,
+        })
+    }
+}
+
+struct Token<'a> {
+    text:&'a str,
+    span:Span,
+}
+
+struct Operands<'a,'b> {
+    tokens:&'b [Token<'a>],
+    /// Where a missing operand is reported.
+    prev:Span,
+}
+
+impl<'a,'b> Operands<'a,'b> {
+    fn next(&mut self,expected:&'static str) -> Result<&'b Token<'a>> {
+        let Some((token,rest)) = self.tokens.split_first() else {
+            let at = Span::new(self.prev.end, self.prev.end, self.prev.line, self.prev.col + self.prev.len() as u32);
+            return Err(Error::Asm(AsmErr::MissingOperand{ expected },at));
+        };
+        self.tokens = rest;
+        self.prev = token.span;
+        Ok(token)
+    }
+
+    fn parse<T:std::str::FromStr>(&mut self,expected:&'static str) -> Result<T> {
+        let token = self.next(expected)?;
+        token.text.parse().map_err(|_| Error::Asm(AsmErr::InvalidOperand{ expected },token.span))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.parse("number")
+    }
+
+    /// A number or `var` for `VAR_COUNT`.
+    fn count(&mut self) -> Result<u16> {
+        let token = self.next("count")?;
+        match token.text {
+            "var" => Ok(VAR_COUNT),
+            x => x.parse().ok()
+                .filter(|x| *x != VAR_COUNT)
+                .ok_or(Error::Asm(AsmErr::InvalidOperand{ expected:"count" },token.span)),
+        }
+    }
+
+    /// A string literal or a bare name.
+    fn name(&mut self) -> Result<String> {
+        let token = self.next("name or string")?;
+        match token.text.strip_prefix('"') {
+            Some(str) => unescape(&str[..str.len()-1], token.span),
+            None => Ok(token.text.to_string()),
+        }
+    }
+
+    fn label(&mut self,func:&mut Func,comp_ctx:&mut CompileCtx) -> Result<LabelId> {
+        let token = self.next("label")?;
+        Ok(func.label(token.text, token.span, comp_ctx))
+    }
+
+    fn end(&self) -> Result<()> {
+        match self.tokens.first() {
+            Some(token) => Err(Error::Asm(AsmErr::UnexpectedOperand,token.span)),
+            None => Ok(()),
+        }
+    }
+}
+
+fn unescape(str:&str,span:Span) -> Result<String> {
+    let mut out = String::with_capacity(str.len());
+    let mut chars = str.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        out.push(match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('0') => '\0',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some(x) => return Err(Error::Asm(AsmErr::InvalidEscape(x),span)),
+            None => return Err(Error::Asm(AsmErr::UnterminatedString,span)),
+        });
+    }
+    Ok(out)
+}
+
+/// Splits every line into whitespace separated tokens, string literals stay whole and comments are dropped.
+fn lines(src:&str) -> Result<Vec<Vec<Token<'_>>>> {
+    let mut lines = vec![];
+    let mut line_start = 0;
+
+    for (line_idx,line) in src.split('\n').enumerate() {
+        let span = |start:usize,end:usize| Span::new(
+            line_start+start,
+            line_start+end,
+            line_idx as u32 + 1,
+            line[..start].chars().count() as u32 + 1,
+        );
+
+        let mut tokens = vec![];
+        let mut chars = line.char_indices().peekable();
+        while let Some((start,c)) = chars.next() {
+            if c == ';' {
+                break;
+            }
+            if c.is_whitespace() {
+                continue;
+            }
+
+            let mut end = start + c.len_utf8();
+            if c == '"' {
+                let mut escaped = false;
+                let mut closed = false;
+                for (i,c) in chars.by_ref() {
+                    end = i + c.len_utf8();
+                    if c == '"' && !escaped {
+                        closed = true;
+                        break;
+                    }
+                    escaped = c == '\\' && !escaped;
+                }
+                if !closed {
+                    return Err(Error::Asm(AsmErr::UnterminatedString,span(start,end)));
+                }
+            } else {
+                while let Some((i,c)) = chars.next_if(|x| !x.1.is_whitespace() && x.1 != ';') {
+                    end = i + c.len_utf8();
+                }
+            }
+            tokens.push(Token{ text:&line[start..end], span:span(start,end) });
+        }
+
+        lines.push(tokens);
+        line_start += line.len() + 1;
+    }
+    Ok(lines)
+}
@@ -0,0 +1,49 @@
+/// A region of the source text.
+/// `start` and `end` are byte offsets, `line` and `col` are 1-based and point at `start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start:usize,
+    pub end:usize,
+    pub line:u32,
+    pub col:u32,
+}
+
+impl Span {
+    pub fn new(start:usize,end:usize,line:u32,col:u32) -> Self {
+        Self { start, end, line, col }
+    }
+
+    /// Span from the start of `self` to the end of `other`.
+    pub fn to(self,other:Span) -> Span {
+        Span {
+            start:self.start,
+            end:other.end.max(self.end),
+            line:self.line,
+            col:self.col,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub node:T,
+    pub span:Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node:T,span:Span) -> Self {
+        Self { node, span }
+    }
+
+    pub fn map<U>(self,f:impl FnOnce(T) -> U) -> Spanned<U> {
+        Spanned { node:f(self.node), span:self.span }
+    }
+}
@@ -1,13 +1,59 @@
-use crate::{asm::{ByteCodeVec, CompileCtx}, ast_gen, bytecode::ByteCode, compiler::FuncCtx, expr::Expr, tokenizer};
+use crate::{asm::{ByteCodeVec, CompileCtx, Layout}, ast_gen, asm::UpvalDesc, bytecode::{ByteCode, CallArgs, VAR_COUNT, VAR_RET_COUNT}, compiler::FuncCtx, disasm::{self, Program}, masm, lout::{LoutFile, SectionKind}, err::{AsmErr, CompilerErr, Error, FormatErr, ParserErr}, expr::Expr, tokenizer, Result};
 
-fn compile_to_file(src:&str,path:&str) {
+fn compile_with_ctx(src:&str) -> Result<(ByteCodeVec,CompileCtx)> {
     let mut comp_ctx = CompileCtx::new();
     let mut bytecode = ByteCodeVec::new();
-    let tokens = &tokenizer::parse(src).unwrap();
-    let block = ast_gen::parse_block(&tokens).unwrap();
-    FuncCtx::new(&[]).compile(&block ,&mut comp_ctx, &mut bytecode, Some(ByteCode::Halt), None);
-    bytecode.print();
-    comp_ctx.write_to_file(bytecode,path);
+    let tokens = tokenizer::parse(src)?;
+    let block = ast_gen::parse_block(&tokens)?;
+    FuncCtx::new(&[]).compile(&block, &mut comp_ctx, &mut bytecode, Some(ByteCode::Halt))?;
+    Ok((bytecode,comp_ctx))
+}
+
+/// The main code followed by the code of every prototype.
+fn compile_src(src:&str) -> Result<Vec<ByteCode>> {
+    let (bytecode,comp_ctx) = compile_with_ctx(src)?;
+    let mut code = bytecode.instrs().to_vec();
+    for proto in comp_ctx.protos() {
+        code.extend_from_slice(proto.code.instrs());
+    }
+    Ok(code)
+}
+
+/// Jumps as the index of the instruction they go to, label ids don't survive encoding.
+fn listing(code:&ByteCodeVec) -> Vec<String> {
+    code.instrs().iter().map(|x| match x {
+        ByteCode::Jump(l) | ByteCode::JumpTrue(l) | ByteCode::JumpFalse(l) => {
+            let target = code.labels().iter().find(|x| x.0 == *l).unwrap().1;
+            format!("{} {}",x.mnemonic(),target)
+        }
+        _ => format!("{:?}",x),
+    }).collect()
+}
+
+/// Encodes `src` in memory and checks that every function decodes to the code it was compiled to.
+fn encode_src(src:&str) -> (Vec<u8>,Program) {
+    let (bytecode,comp_ctx) = compile_with_ctx(src).unwrap();
+    let bytes = comp_ctx.encode(&bytecode).unwrap();
+    let program = Program::decode(&bytes).unwrap();
+    assert_eq!(listing(&program.main.code),listing(&bytecode));
+    assert_eq!(program.protos.len(),comp_ctx.protos().len());
+    for (decoded,proto) in program.protos.iter().zip(comp_ctx.protos()) {
+        assert_eq!(listing(&decoded.code),listing(&proto.code));
+    }
+    (bytes,program)
+}
+
+/// Writes `tests/<name>.lout` for the interpreter tests. The `*_test_file` tests are the fixture
+/// generators, this is the only place the Rust tests touch the filesystem.
+fn write_fixture(name:&str,bytes:&[u8]) {
+    std::fs::write(format!("../tests/{name}.lout"),bytes).unwrap();
+}
+
+/// Generates the fixture `name` from `src` and returns the decoded program to assert on.
+fn compile_fixture(src:&str,name:&str) -> Program {
+    let (bytes,program) = encode_src(src);
+    write_fixture(name,&bytes);
+    program
 }
 
 
@@ -23,23 +69,29 @@ pub fn compatibility_test_file() {
     bytecode.add_instr(ByteCode::Load(2));
     bytecode.add_instr(ByteCode::Add);
     bytecode.add_instr(ByteCode::Halt);
-    comp_ctx.write_to_file(bytecode,"../tests/compat.lout");
+    let bytes = comp_ctx.encode(&bytecode).unwrap();
+    write_fixture("compat",&bytes);
+
+    let (assembled,asm_ctx) = masm::assemble(&std::fs::read_to_string("../tests/compat.masm").unwrap()).unwrap();
+    assert_eq!(asm_ctx.encode(&assembled).unwrap(),bytes);
 }
 
 
 #[test]
 pub fn assing_declaration_test_file() {
-    compile_to_file("
+    let program = compile_fixture("
         local x,y,z = 10,12.1,\"hello\";
         x,y,z = (x+1)*y,x,z..\"world\"..10.1;
-        ","../tests/assing_dec.lout"
+        ","assing_dec"
     );
+    assert_eq!(program.names,["hello".into(),"world".into()]);
+    assert!(program.protos.is_empty());
 }
 
 
 #[test]
 pub fn func_comp_test_file() {
-    compile_to_file("
+    let program = compile_fixture("
         function a(x) {
             return b(x+1);
         }
@@ -49,13 +101,17 @@ pub fn func_comp_test_file() {
         }
 
         local x = a(10); 
-    ","../tests/func_comp.lout");
+    ","func_comp");
+    // `a` and `b` are locals of the main chunk, `a` reaches `b` through an upvalue
+    assert!(program.names.is_empty());
+    assert_eq!(program.protos.iter().map(|x| (&*x.name,x.arg_count)).collect::<Vec<_>>(),[("a",1),("b",1)]);
+    assert_eq!(program.protos[0].upvals,[UpvalDesc::Local(2)]);
 }
 
 
 #[test]
 pub fn inline_func_test_file() {
-    compile_to_file("
+    let program = compile_fixture("
         function new_counter(x) {
             local step = 10;
             return function() {
@@ -67,22 +123,28 @@ pub fn inline_func_test_file() {
         local counter = new_counter(3);
         local x = counter();
         local y = counter();
-    ","../tests/inline_func.lout");
+    ","inline_func");
+    assert_eq!(program.protos.iter().map(|x| (&*x.name,x.arg_count)).collect::<Vec<_>>(),[("(anonymous)",0),("new_counter",1)]);
+    assert_eq!(program.protos[0].upvals,[UpvalDesc::Local(1),UpvalDesc::Local(2)]);
 }
 
 #[test]
 pub fn while_test_file() {
-    compile_to_file("
+    let program = compile_fixture("
         local i = 0;
         while 10 > i {
             i = i+1;
         }
-    ","../tests/while.lout");
+    ","while");
+    let code = program.main.code.instrs();
+    assert!(program.protos.is_empty());
+    assert_eq!(code.iter().filter(|x| matches!(x,ByteCode::JumpFalse(_))).count(),1);
+    assert_eq!(code.iter().filter(|x| matches!(x,ByteCode::Jump(_))).count(),1);
 }
 
 #[test]
 pub fn if_else_test_file() {
-    compile_to_file("
+    let program = compile_fixture("
         local x = true;
         local y = 1;
         if x {
@@ -90,17 +152,648 @@ pub fn if_else_test_file() {
         } else {
             y = 3; 
         }
-    ","../tests/while.lout");
+    ","if_else");
+    let code = program.main.code.instrs();
+    assert!(program.protos.is_empty());
+    assert_eq!(code.iter().filter(|x| matches!(x,ByteCode::JumpFalse(_))).count(),1);
+    assert_eq!(code.iter().filter(|x| matches!(x,ByteCode::Jump(_))).count(),1);
 }
 
 #[test]
 pub fn table_test_file() {
-    compile_to_file("
+    let program = compile_fixture("
         local x = {};
         local y = {1,3.14,{},x=10};
         x.x,y[2] = 10,x;
         y[0] = #y;
-    ","../tests/table.lout");
+    ","table");
+    assert_eq!(program.names,["x".into()]);
+    assert_eq!(program.main.code.instrs().iter().filter(|x| matches!(x,ByteCode::NewTable(_))).count(),3);
+}
+
+
+
+#[test]
+pub fn global_test_file() {
+    let program = compile_fixture("
+        x = 10;
+        local y = x+1;
+        function f(a) {
+            x = x*a;
+            return x;
+        }
+        z = f(y);
+    ","global");
+    assert_eq!(program.names,["x".into(),"z".into()]);
+    assert_eq!((&*program.protos[0].name,program.protos[0].arg_count),("f",1));
+    assert!(program.protos[0].upvals.is_empty());
+}
+
+#[test]
+pub fn for_test_file() {
+    let program = compile_fixture("
+        local t = {1,2,3,x=4};
+        local sum = 0;
+        for i,v in ipairs t {
+            sum = sum+v;
+        }
+        local seq = 0;
+        for k,v in kvpairs t {
+            local x = v;
+            seq = seq*10+x;
+        }
+        for i in range 10 {
+            local x = i*2;
+            if x > 6 {
+                break;
+            }
+            sum = sum+i;
+        }
+        local n = 0;
+        local next_value = function() {
+            if n < 3 {
+                n = n+1;
+                return n;
+            }
+            return nil;
+        };
+        for x in next_value {
+            sum = sum+x*100;
+        }
+    ","for");
+    assert_eq!(program.names,["x".into()]);
+    assert_eq!(program.protos[0].upvals,[UpvalDesc::Local(4)]);
+}
+
+#[test]
+pub fn and_or_test_file() {
+    let program = compile_fixture("
+        local calls = 0;
+        local hit = function(x) { calls = calls+1; return x; };
+        local t = nil;
+        local a = t and t.x;
+        local b = false or hit(2);
+        local c = hit(false) and hit(1);
+        local d = hit(3) or hit(4);
+        local e = a == nil and b or c;
+    ","and_or");
+    assert_eq!(program.names,["x".into()]);
+    assert!(!program.main.code.instrs().iter().any(|x| matches!(x,ByteCode::GetGlobal(_))));
+}
+
+#[test]
+pub fn call_test_file() {
+    let program = compile_fixture("
+        function step() {
+            return 4;
+        }
+        local mt = {update = function(self, dt) { self.x = self.x + dt; return self.x; }};
+        local obj = setmetatable({x=1}, mt);
+        obj:update(2);
+        local y = obj:update(obj.x, 0);
+        local z = obj:update(step());
+    ","call");
+    assert_eq!(program.names,["update".into(),"x".into(),"setmetatable".into()]);
+    assert_eq!(program.protos.iter().map(|x| (&*x.name,x.arg_count)).collect::<Vec<_>>(),[("step",0),("(anonymous)",2)]);
+    assert_eq!(program.main.code.instrs().iter().filter(|x| **x == ByteCode::GetMethod(0)).count(),3);
+}
+
+#[test]
+pub fn multi_return_test_file() {
+    let program = compile_fixture("
+        function pair(x) {
+            return x, x*2;
+        }
+        function forward(x) {
+            return 1, pair(x);
+        }
+        local a, b, c = forward(1);
+        local d = pair(2);
+        local e, f, g = 1, 2;
+        local t = {};
+        a, t.x, b = pair(3);
+        pair(4);
+    ","multi_return");
+    assert_eq!(program.protos.iter().map(|x| (&*x.name,x.arg_count)).collect::<Vec<_>>(),[("pair",1),("forward",1)]);
+    assert_eq!(program.protos[1].upvals,[UpvalDesc::Local(1)]);
+}
+
+#[test]
+pub fn multi_return_test() {
+    let calls = |src:&str| -> Vec<u16> {
+        compile_src(src).unwrap().iter()
+            .filter_map(|x| match x {
+                ByteCode::Call(CallArgs { ret_count, .. }) => Some(*ret_count),
+                _ => None,
+            })
+            .collect()
+    };
+    assert_eq!(calls("local a,b,c = f(), g();"),vec![1,2]);
+    assert_eq!(calls("local a = f(), g();"),vec![1,1]);
+    assert_eq!(calls("f(); a,b = g();"),vec![0,2]);
+    assert_eq!(calls("local x = function() { return f(), g(); };"),vec![1,VAR_RET_COUNT]);
+
+    let code = compile_src("local a = 1, 2; local b, c = 3;").unwrap();
+    assert_eq!(code[..5],[ByteCode::LoadInt(1),ByteCode::LoadInt(2),ByteCode::Pop,ByteCode::LoadInt(3),ByteCode::LoadNil]);
+
+    let code = compile_src("local x = function() { return 1, f(); };").unwrap();
+    assert!(code.ends_with(&[ByteCode::LoadInt(1),ByteCode::Add,ByteCode::Ret(VAR_COUNT),ByteCode::Ret(0)]));
+}
+
+#[test]
+pub fn operator_test() {
+    let code = compile_src("local a = 1; local x = 7 // 2 != -a;").unwrap();
+    assert_eq!(code[1..7],[ByteCode::LoadInt(7),ByteCode::LoadInt(2),ByteCode::IDiv,ByteCode::Load(1),ByteCode::Neg,ByteCode::Eq(false)]);
+
+    let code = compile_src("local a = -2147483648; local b = -0x80000000; local c = - -1;").unwrap();
+    assert_eq!(code[..4],[ByteCode::LoadInt(i32::MIN),ByteCode::LoadInt(i32::MIN),ByteCode::LoadInt(-1),ByteCode::Neg]);
+    // The sign is part of the literal wherever it is written.
+    let code = compile_src("local a = - 2147483648; local b = - -- comment\n 2147483648; local c = -(2147483648);").unwrap();
+    assert_eq!(code[..3],[ByteCode::LoadInt(i32::MIN),ByteCode::LoadInt(i32::MIN),ByteCode::LoadInt(i32::MIN)]);
+
+    let overflow = |src:&str| match compile_src(src) {
+        Err(Error::Compiler(CompilerErr::IntOverflow,span)) => (span.start,span.end),
+        x => panic!("{:?}",x.map(|_| ())),
+    };
+    assert_eq!(overflow("local x = 2147483648;"),(10,20));
+    assert_eq!(overflow("local x = 1 - 2147483648;"),(14,24));
+    assert_eq!(overflow("local x = -2147483648 ^ 2;"),(11,21));
+    assert_eq!(overflow("local x = -4294967295;"),(11,21));
+    assert_eq!(overflow("local t = {2147483648 = 1};"),(10,26));
+
+    let code = compile_src("local s = \"a\"; s ..= \"b\";").unwrap();
+    assert_eq!(code,compile_src("local s = \"a\"; s = s .. \"b\";").unwrap());
+    assert!(code.contains(&ByteCode::Concat));
+
+    // The table and key are evaluated once.
+    let code = compile_src("local t = {}; t[g()] ..= \"x\"; local is = 1;").unwrap();
+    assert_eq!(code.iter().filter(|x| matches!(x,ByteCode::GetGlobal(_))).count(),1);
+    assert!(code.windows(6).any(|x| x == [ByteCode::Load(2),ByteCode::Load(3),ByteCode::Get,ByteCode::LoadStr(1),ByteCode::Concat,ByteCode::SetPop]));
+}
+
+#[test]
+pub fn method_receiver_test() {
+    // `t` is local 1, the return slot of the call is at 3 and the receiver at 4.
+    // The receiver is the first argument, `GetMethod` replaces the copy of it above the arguments.
+    assert_eq!(compile_src("local t = {}; local x = 1 + t:f();").unwrap(),vec![
+        ByteCode::NewTable(0), ByteCode::LoadInt(1),
+        ByteCode::LoadNil, ByteCode::Load(1),
+        ByteCode::Load(4), ByteCode::GetMethod(0), ByteCode::Call(CallArgs{ arg_count:1, ret_count:1 }),
+        ByteCode::Add, ByteCode::Halt,
+    ]);
+    assert_eq!(compile_src("local t = {}; local a, b = 1, t:m(2);").unwrap(),vec![
+        ByteCode::NewTable(0), ByteCode::LoadInt(1),
+        ByteCode::LoadNil, ByteCode::Load(1), ByteCode::LoadInt(2),
+        ByteCode::Load(4), ByteCode::GetMethod(0), ByteCode::Call(CallArgs{ arg_count:2, ret_count:1 }),
+        ByteCode::Halt,
+    ]);
+    // The spread count is one short of the arguments, the receiver is added to it.
+    assert_eq!(compile_src("local t = {}; t:m(t:n());").unwrap(),vec![
+        ByteCode::NewTable(0),
+        ByteCode::LoadNil, ByteCode::Load(1),
+        ByteCode::LoadNil, ByteCode::Load(1),
+        ByteCode::Load(5), ByteCode::GetMethod(0), ByteCode::Call(CallArgs{ arg_count:1, ret_count:VAR_RET_COUNT }),
+        ByteCode::LoadInt(1), ByteCode::Add,
+        ByteCode::Load(3), ByteCode::GetMethod(1), ByteCode::Call(CallArgs{ arg_count:VAR_COUNT, ret_count:0 }),
+        ByteCode::Halt,
+    ]);
+}
+
+#[test]
+pub fn varargs_test_file() {
+    let program = compile_fixture("
+        function count(...) {
+            return #{...};
+        }
+        function pick(x, y, ...) {
+            local a, b = ...;
+            return y, count(...), a, b;
+        }
+        local f = function(...) { return 1, ...; };
+        local y, n, a, b = pick(0, f(2, 3, 4));
+        local y2, n2, a2, b2 = pick(0);
+    ","varargs");
+    assert_eq!(program.protos.iter().map(|x| (&*x.name,x.arg_count,x.var_args)).collect::<Vec<_>>(),[("count",0,true),("pick",2,true),("(anonymous)",0,true)]);
 }
 
+#[test]
+pub fn varargs_test() {
+    let code = compile_src("local f = function(x, ...) { g(x, ...); local t = {...}; local a, b = ...; };").unwrap();
+    assert!(code.windows(3).any(|x| x == [ByteCode::LoadVarArgs(VAR_COUNT),ByteCode::LoadInt(1),ByteCode::Add]));
+    assert!(code.contains(&ByteCode::Call(CallArgs { arg_count: VAR_COUNT, ret_count: 0 })));
+    assert!(code.windows(2).any(|x| x == [ByteCode::LoadVarArgs(VAR_COUNT),ByteCode::PushAll]));
+    assert!(code.contains(&ByteCode::LoadVarArgs(2)));
+
+    let code = compile_src("f(1, g());").unwrap();
+    assert!(code.contains(&ByteCode::Call(CallArgs { arg_count: VAR_COUNT, ret_count: 0 })));
+
+    let (_,comp_ctx) = compile_with_ctx("local f = function(x, ...) {}; function g(a, b) {}").unwrap();
+    assert_eq!(comp_ctx.protos().iter().map(|x| (x.arg_count,x.var_args)).collect::<Vec<_>>(),[(1,true),(2,false)]);
+}
+
+#[test]
+pub fn nested_upval_test_file() {
+    let src = "
+        local x = 1;
+        function outer() {
+            local y = 2;
+            return function() {
+                return function() {
+                    x = x + y;
+                    return x;
+                };
+            };
+        }
+        local f = outer()();
+        local a = f();
+        local b = f();
+    ";
+    let program = compile_fixture(src,"nested_upval");
+    assert_eq!(program.protos[0].upvals,[UpvalDesc::Upval(0),UpvalDesc::Upval(1)]);
+    assert_eq!(program.protos[1].upvals,[UpvalDesc::Upval(0),UpvalDesc::Local(1)]);
+    assert_eq!(program.protos[2].upvals,[UpvalDesc::Local(2)]);
 
+    // `x` comes from the main chunk and `y` from `outer`, neither is a global.
+    let code = compile_src(src).unwrap();
+    assert!(code.contains(&ByteCode::GetUpval(0)) && code.contains(&ByteCode::GetUpval(1)) && code.contains(&ByteCode::SetUpval(0)));
+    assert!(!code.iter().any(|x| matches!(x,ByteCode::GetGlobal(_)|ByteCode::SetGlobal(_))));
+}
+
+#[test]
+pub fn nested_upval_test() {
+    let (_,comp_ctx) = compile_with_ctx("
+        function a() {
+            local x = 1;
+            local b = function() {
+                local c = function() { return x; };
+            };
+        }
+    ").unwrap();
+
+    // `b` captures `x` from `a`, `c` captures it from `b`
+    let protos = comp_ctx.protos();
+    assert_eq!(protos.iter().map(|x| &*x.name).collect::<Vec<_>>(),["(anonymous)","(anonymous)","a"]);
+    assert_eq!(protos[0].upvals,[UpvalDesc::Upval(0)]);
+    assert_eq!(protos[1].upvals,[UpvalDesc::Local(1)]);
+    assert!(protos[2].upvals.is_empty());
+    assert!(!protos.iter().any(|x| x.code.instrs().iter().any(|x| matches!(x,ByteCode::GetGlobal(_)))));
+
+    // Function statements see the locals declared before them.
+    let (_,comp_ctx) = compile_with_ctx("local a = 1; function f() { return a; }").unwrap();
+    assert_eq!(comp_ctx.protos()[0].upvals,[UpvalDesc::Local(2)]);
+    assert_eq!(comp_ctx.protos()[0].code.instrs()[0],ByteCode::GetUpval(0));
+}
+
+#[test]
+pub fn proto_test() {
+    let (main,comp_ctx) = compile_with_ctx("
+        function f(a, b) {
+            return a + b * g(a, 1);
+        }
+        function g(x, y) {
+            return f(y, x);
+        }
+    ").unwrap();
+
+    // both slots exist before the closures are created, so they can capture each other
+    assert_eq!(main.instrs()[..6],[ByteCode::LoadNil,ByteCode::LoadNil,ByteCode::Closure(0),ByteCode::Write(1),ByteCode::Closure(1),ByteCode::Write(2)]);
+    assert_eq!(main.max_stack(),4);
+
+    let f = &comp_ctx.protos()[0];
+    assert_eq!((&*f.name,f.arg_count,f.var_args),("f",2,false));
+    assert_eq!(f.upvals,[UpvalDesc::Local(2)]);
+    // slot 0, `a`, `b`, then `a`, `b`, the return slot, `a`, `1` and `g`
+    assert_eq!(f.max_stack,3+6);
+}
+
+#[test]
+pub fn shared_upval_test_file() {
+    let program = compile_fixture("
+        function counter() {
+            local n = 0;
+            local inc = function() { n = n + 1; };
+            local get = function() { return n; };
+            inc();
+            return inc, get, n;
+        }
+        local inc, get, n = counter();
+        inc();
+        local a = get();
+        local fs = {};
+        for i in range 10 {
+            local x = i;
+            fs[i] = function() { return i + x; };
+            if i == 2 {
+                break;
+            }
+        }
+        local b = fs[0]() + fs[1]() + fs[2]();
+    ","shared_upval");
+    // `inc` and `get` share the upvalue of `n`
+    assert_eq!(program.protos[0].upvals,[UpvalDesc::Local(1)]);
+    assert_eq!(program.protos[1].upvals,[UpvalDesc::Local(1)]);
+}
+
+#[test]
+pub fn close_upval_test() {
+    let code = |src:&str| compile_src(src).unwrap();
+
+    let c = code("local f = function() { if true { local y = 1; local x = 2; local g = function() { return x; }; } };");
+    assert!(c.windows(4).any(|x| x == [ByteCode::Close(1),ByteCode::Pop,ByteCode::Pop,ByteCode::Pop]));
+    assert!(c.windows(2).any(|x| x == [ByteCode::Close(1),ByteCode::Ret(0)]));
+
+    // a capture after the return in source order still closes on return
+    let c = code("local f = function(a) { while true { if a { return 1; } local g = function() { return a; }; } };");
+    assert!(c.windows(2).any(|x| x == [ByteCode::Close(1),ByteCode::Ret(1)]));
+    assert!(c.windows(2).any(|x| x == [ByteCode::Close(1),ByteCode::Ret(0)]));
+
+    let c = code("while true { local x = 1; local g = function() { return x; }; if x { break; } }");
+    let break_jump = c.iter().position(|x| matches!(x,ByteCode::Jump(_))).unwrap();
+    assert_eq!(c[break_jump-3..break_jump],[ByteCode::Close(1),ByteCode::Pop,ByteCode::Pop]);
+
+    let c = code("for i in range 3 { local g = function() { return i; }; }");
+    // `i` is closed every iteration, the hidden loop state when the loop ends
+    assert!(c.windows(2).any(|x| x == [ByteCode::Close(3),ByteCode::Load(2)]));
+    assert!(c.windows(2).any(|x| x == [ByteCode::Close(1),ByteCode::Pop]));
+
+    assert!(!code("local x = 1; local f = function() { return 1; };").iter().any(|x| matches!(x,ByteCode::Close(_))));
+}
+
+#[test]
+pub fn lout_file_test() {
+    let (bytes,_) = encode_src("local t = {}; t.name = \"x\"; print(t.name);");
+    write_fixture("lout_file",&bytes);
+    let file = LoutFile::read(&bytes).unwrap();
+    assert_eq!(file.names().unwrap(),["x","name","print"]);
+    assert!(file.section(SectionKind::Main).unwrap().starts_with(b"(main)\0"));
+    assert_eq!(file.section(SectionKind::Protos).unwrap(),[0,0,0,0]);
+}
+
+#[test]
+pub fn encode_test() {
+    let (bytecode,comp_ctx) = compile_with_ctx("local f = function(x) { return x; }; print(f(1));").unwrap();
+    let bytes = comp_ctx.encode(&bytecode).unwrap();
+
+    let mut written = vec![];
+    comp_ctx.write_to(&bytecode, &mut written).unwrap();
+    assert_eq!(bytes,written);
+
+    let file = LoutFile::read(&bytes).unwrap();
+    assert_eq!(file.names().unwrap(),["print"]);
+    assert!(file.section(SectionKind::Protos).unwrap().starts_with(b"\x01\0\0\0(anonymous)\0"));
+}
+
+#[test]
+pub fn decode_test() {
+    let (bytecode,comp_ctx) = compile_with_ctx("
+        local t = {1, 2.5, \"s\", x = true};
+        function f(a, ...) {
+            for k, v in kvpairs t {
+                if v == a and k { return ...; }
+            }
+            local g = function() { return a or #t; };
+            while a > 0 { a = a - 1; if a == 3 { break; } }
+            return g(), t:len();
+        }
+        print(f(1, 2, 3));
+    ").unwrap();
+    let program = Program::decode(&comp_ctx.encode(&bytecode).unwrap()).unwrap();
+
+    assert_eq!(program.names,["s".into(),"x".into(),"len".into(),"print".into()]);
+    assert_eq!(listing(&program.main.code),listing(&bytecode));
+    assert_eq!(program.main.max_stack,bytecode.max_stack());
+    assert_eq!(program.protos.len(),comp_ctx.protos().len());
+    for (decoded,proto) in program.protos.iter().zip(comp_ctx.protos()) {
+        assert_eq!(listing(&decoded.code),listing(&proto.code));
+        assert_eq!((&decoded.name,decoded.arg_count,decoded.var_args,&decoded.upvals,decoded.max_stack),
+            (&proto.name,proto.arg_count,proto.var_args,&proto.upvals,proto.max_stack));
+    }
+}
+
+#[test]
+pub fn disassemble_test() {
+    let (bytecode,comp_ctx) = compile_with_ctx("
+        local f = function(x) { while x { x = g(\"s\"); } };
+    ").unwrap();
+    let listing = Program::decode(&comp_ctx.encode(&bytecode).unwrap()).unwrap().disassemble();
+    assert_eq!(listing.lines().collect::<Vec<_>>(),[
+        "function main `(main)`: args=0 var_args=false max_stack=2",
+        "    0000  closure 0                 ; function 0 `(anonymous)`",
+        "    0001  halt",
+        "",
+        "function 0 `(anonymous)`: args=1 var_args=false max_stack=5",
+        "L0:",
+        "    0000  load 1",
+        "    0001  jump_false L1",
+        "    0002  load_nil",
+        "    0003  load_str 0                ; \"s\"",
+        "    0004  get_global 1              ; g",
+        "    0005  call 1 1",
+        "    0007  write 1",
+        "    0008  jump L0",
+        "L1:",
+        "    0009  ret 0",
+    ]);
+
+    let mut bytes = comp_ctx.encode(&bytecode).unwrap();
+    assert!(Program::decode(&bytes[..bytes.len()-1]).is_err());
+    let len = bytes.len();
+    bytes[len-2] = 0xEE;
+    assert!(Program::decode(&bytes).is_err());
+}
+
+#[test]
+pub fn loop_stack_test() {
+    let bytecode = compile_src("
+        local t = {};
+        while true {
+            local a = 1;
+            if a {
+                local b = 2;
+                break;
+            }
+            local c = 3;
+        }
+    ").unwrap();
+    let code:Vec<ByteCode> = bytecode;
+
+    let break_jump = code.iter().position(|x| matches!(x,ByteCode::Jump(_))).unwrap();
+    assert_eq!(code[break_jump-2..break_jump],[ByteCode::Pop,ByteCode::Pop]);
+    assert_eq!(code.iter().filter(|x| **x == ByteCode::Pop).count(),2+1+2);
+}
+
+#[test]
+pub fn error_test() {
+    assert!(matches!(compile_src("local x = 1; return x"), Err(Error::Parser(ParserErr::UnexpectedEnd { .. },_))));
+    assert!(matches!(compile_src("local x = ;"), Err(Error::Parser(ParserErr::UnexpectedToken { expected: "expression", .. },_))));
+    assert!(matches!(compile_src("local x = 1, ;"), Err(Error::Parser(ParserErr::UnexpectedToken { expected: "expression", .. },_))));
+    assert!(matches!(compile_src("break;"), Err(Error::Compiler(CompilerErr::BreakOutsideLoop,_))));
+    assert!(matches!(compile_src("local x = 1; x+1 = 2;"), Err(Error::Compiler(CompilerErr::InvalidAssingTarget,_))));
+    assert!(matches!(compile_src("local x = (1;"), Err(Error::Parser(..))));
+    assert!(matches!(compile_src("local x = $;"), Err(Error::Tokenizer(..))));
+    assert!(matches!(compile_src("for i,x in range y {}"), Err(Error::Compiler(CompilerErr::TooManyLoopVars { max: 1 },_))));
+    assert!(matches!(compile_src("local x = ...;"), Err(Error::Compiler(CompilerErr::VarArgsOutsideVarArgFunction,_))));
+    assert!(matches!(compile_src("function f(...) { local g = function() { return ...; }; }"), Err(Error::Compiler(CompilerErr::VarArgsOutsideVarArgFunction,_))));
+    assert!(matches!(compile_src("function f(..., x) {}"), Err(Error::Parser(..))));
+}
+
+#[test]
+pub fn truncated_source_test() {
+    let src = "
+        function f(x,y) {
+            local t = {1,2,a=x,[3]=y};
+            while x > 0 {
+                if x == 2 { break; } else { x = x-1; }
+            }
+            return t:get(x)[y] .. \"end\";
+        }
+        local z = f(1,2);
+    ";
+    for end in 0..src.len() {
+        _ = compile_src(&src[..end]);
+    }
+}
+
+
+#[test]
+pub fn assemble_test() {
+    let (bytecode,comp_ctx) = compile_with_ctx("
+        function add(a,b) { return a+b; }
+        local x = add(1,2);
+        while x < 10 { x = x+1; }
+        print(\"x\", x);
+    ").unwrap();
+
+    let (assembled,asm_ctx) = masm::assemble(r#"
+        .function add args=2
+            load 1
+            load 2
+            add
+            ret 1
+            ret 0
+        .end
+
+            load_nil
+            closure @add
+            write 1
+            load_nil
+            load_int 1
+            load_int 2
+            load 1
+            call 2 1
+        loop:
+            load 2
+            load_int 10
+            less true
+            jump_false end
+            load 2
+            load_int 1
+            add
+            write 2
+            jump loop
+        end:
+            load_nil
+            load_str "x"        ; a comment
+            load 2
+            get_global print
+            call 2 0
+            halt
+    "#).unwrap();
+
+    assert_eq!(asm_ctx.encode(&assembled).unwrap(),comp_ctx.encode(&bytecode).unwrap());
+}
+
+#[test]
+pub fn assemble_error_test() {
+    let err = |src:&str| match masm::assemble(src) {
+        Err(Error::Asm(err,span)) => (err,span.line),
+        _ => panic!("{:?} assembled",src),
+    };
+
+    assert_eq!(err("load_nil\nfoo 1"),(AsmErr::UnknownInstruction("foo".into()),2));
+    assert_eq!(err(".func f"),(AsmErr::UnknownDirective(".func".into()),1));
+    assert_eq!(err("load"),(AsmErr::MissingOperand{ expected:"number" },1));
+    assert_eq!(err("load x"),(AsmErr::InvalidOperand{ expected:"number" },1));
+    assert_eq!(err("add 1"),(AsmErr::UnexpectedOperand,1));
+    assert_eq!(err("load_str \"abc"),(AsmErr::UnterminatedString,1));
+    assert_eq!(err("load_str \"\\q\""),(AsmErr::InvalidEscape('q'),1));
+    assert_eq!(err("jump end"),(AsmErr::UnknownLabel("end".into()),1));
+    assert_eq!(err("a:\na:"),(AsmErr::DuplicateLabel("a".into()),2));
+    assert_eq!(err("closure @f"),(AsmErr::UnknownFunction("f".into()),1));
+    assert_eq!(err(".function f\n.end\n.function f\n.end"),(AsmErr::DuplicateFunction("f".into()),3));
+    assert_eq!(err(".function f\n.function g"),(AsmErr::NestedFunction,2));
+    assert_eq!(err(".function f\nret 0"),(AsmErr::UnclosedFunction,1));
+    assert_eq!(err(".end"),(AsmErr::OutsideOfFunction(".end"),1));
+
+    // Labels are local to their function.
+    assert_eq!(err("a:\n.function f\njump a\n.end"),(AsmErr::UnknownLabel("a".into()),3));
+}
+
+#[test]
+pub fn long_jump_test() {
+    // Every `x = x+1;` is 5 words, so the loop body is far longer than an `i16` offset reaches.
+    let src = format!("local x = 0; while x < 10 {{ {} }} local y = x;", "x = x+1; ".repeat(10000));
+    let (bytecode,comp_ctx) = compile_with_ctx(&src).unwrap();
+    let bytes = comp_ctx.encode(&bytecode).unwrap();
+
+    let program = Program::decode(&bytes).unwrap();
+    assert_eq!(program.main.code.len(),bytecode.len());
+    let mut targets:Vec<usize> = bytecode.labels().iter().map(|x| x.1).collect();
+    targets.sort();
+    assert_eq!(program.main.code.labels().iter().map(|x| x.1).collect::<Vec<usize>>(),targets);
+
+    let disasm = program.disassemble();
+    assert!(disasm.contains("jump_false L1") && disasm.contains("jump L0"));
+    let layout = Layout::of(&bytecode);
+    assert_eq!(layout.long.iter().filter(|x| **x).count(),2);
+    assert!(comp_ctx.encode(&program.main.code).unwrap() == bytes);
+}
+
+#[test]
+pub fn wide_operand_test() {
+    // 70000 distinct strings and locals, and a call with as many arguments.
+    let mut src = String::new();
+    for i in 0..70000 {
+        src += &format!("local x = \"{}\";", i);
+    }
+    src += "print(x); f(";
+    src += &"1,".repeat(70000);
+    src += "1);";
+
+    let (bytecode,comp_ctx) = compile_with_ctx(&src).unwrap();
+    let code = bytecode.instrs();
+    assert!(code.contains(&ByteCode::LoadStr(69999)));
+    assert!(code.contains(&ByteCode::Load(70000)));
+    assert!(code.windows(3).any(|x| matches!(x,[ByteCode::LoadInt(70001),ByteCode::GetGlobal(_),ByteCode::Call(CallArgs{ arg_count:VAR_COUNT, .. })])));
+
+    let bytes = comp_ctx.encode(&bytecode).unwrap();
+    let program = Program::decode(&bytes).unwrap();
+    assert!(program.main.code.instrs() == code);
+    assert_eq!(program.names.len(),70002);
+    assert!(program.disassemble().contains("load 70000"));
+
+    let extend_arg = |x:u16| u32::from_ne_bytes([x as u8,(x >> 8) as u8,crate::bytecode::EXTEND_ARG,0]);
+    let load = u32::from_ne_bytes([1,0,7,0]);
+    assert_eq!(disasm::decode_code(&[extend_arg(1),load]).unwrap().instrs(),[ByteCode::Load(0x10001)]);
+    assert!(matches!(disasm::decode_code(&[extend_arg(0),load]), Err(Error::Format(FormatErr::InvalidExtendArg))));
+    assert!(matches!(disasm::decode_code(&[extend_arg(1)]), Err(Error::Format(FormatErr::Truncated))));
+
+    let names = |n:usize| (0..n).map(|i| format!("a{}",i)).collect::<Vec<String>>().join(",");
+    let src = format!("function f({}) {{}} local {} = f();", names(300), names(300));
+    let (bytecode,comp_ctx) = compile_with_ctx(&src).unwrap();
+    assert_eq!(comp_ctx.protos()[0].arg_count,300);
+    assert!(bytecode.instrs().contains(&ByteCode::Call(CallArgs{ arg_count:0, ret_count:300 })));
+    let program = Program::decode(&comp_ctx.encode(&bytecode).unwrap()).unwrap();
+    assert_eq!(program.protos[0].arg_count,300);
+    assert!(program.main.code.instrs() == bytecode.instrs());
+
+    let src = format!("function f({}) {{}}", names(70000));
+    assert!(matches!(compile_src(&src), Err(Error::Compiler(CompilerErr::TooManyParams { max: 65535 },_))));
+}
+
+#[test]
+pub fn utf8_test() {
+    let (bytecode,comp_ctx) = compile_with_ctx("local grüße = \"Grüße, 世界\"; print(grüße);").unwrap();
+    let bytes = comp_ctx.encode(&bytecode).unwrap();
+    assert_eq!(LoutFile::read(&bytes).unwrap().names().unwrap(),["Grüße, 世界","print"]);
+}
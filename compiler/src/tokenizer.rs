@@ -1,4 +1,8 @@
-use std::{hash::Hash, iter::Peekable, str::Bytes};
+use std::hash::Hash;
+
+use unicode_xid::UnicodeXID;
+
+use crate::{err::{Error, Result, TokenizerErr}, span::{Span, Spanned}};
 
 #[derive(Debug,Clone,PartialEq)]
 pub enum Token {
@@ -14,11 +18,13 @@ pub enum Token {
 
     Nil,
     BoolLiteral(bool),
-    IntLiteral(i32),
+    /// Decimal literals up to `u32::MAX`, hex and binary ones as the `i32` with their bits.
+    /// The compiler checks that decimal ones fit, `-2147483648` only does once it is negated.
+    IntLiteral(i64),
     FloatLiteral(f32),
     StrLiteral(Box<str>),
 
-    Assing,
+    Assing,ConcatAssing,
 
     Add,Sub,Div,Mul,IDiv,Mod,Pow,Concat,
     And,Or,Xor,Shr,Shl,
@@ -27,7 +33,7 @@ pub enum Token {
     Eq,NotEq,Less,LessEq,Greater,GreaterEq,Is,
 
     RoundO,RoundC,CurlyO,CurlyC,SquareO,SquareC,
-    Colon,Comma,Dot
+    Colon,Comma,Dot,VarArgs
 }
 
 impl Token {
@@ -47,232 +53,420 @@ impl Hash for Token {
 
 impl Eq for Token {}
 
-#[derive(Debug)]
-pub enum TokenizerErr {
-    NonAscii,
-    InvalidSymbol(u8),
-    EarlyEOF,
+/// Comments, which the parser never sees.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Trivia {
+    /// `-- text`
+    Comment(Box<str>),
+    /// `--- text`, documents what follows it.
+    DocComment(Box<str>),
+    /// `--[[ text ]]`, these nest.
+    BlockComment(Box<str>),
 }
 
-type Result<T> = std::result::Result<T,TokenizerErr>;
+pub fn parse(str:&str) -> Result<Vec<Spanned<Token>>> {
+    parse_with_trivia(str, &mut vec![])
+}
 
-pub fn parse(str:&str) -> Result<Vec<Token>> {
-    let mut tokens = Vec::new();
-    match parse_rec(&mut tokens, &mut str.bytes().peekable()) {
-        Ok(_) => {},
-        Err(e) => {
-            println!("{:?}",tokens);
-            return Err(e);
-        }
-    }
-    Ok(tokens)
+/// Also collects the comments, for tools that have to keep them.
+pub fn parse_with_trivia(str:&str,trivia:&mut Vec<Spanned<Trivia>>) -> Result<Vec<Spanned<Token>>> {
+    let mut tokens:Vec<Spanned<Token>> = Vec::new();
+    let mut lexer = Lexer::new(str);
+    let result = lexer.parse_all(&mut tokens);
+    trivia.append(&mut lexer.trivia);
+    result.map(|_| tokens)
 }
 
 
-fn parse_rec(tokens:&mut Vec<Token>,bytes:&mut Peekable<Bytes>) -> Result<()> {
-    let byte = match bytes.next() {
-        Some(byte) => byte,
-        None => return Ok(()),
-    };
+struct Lexer<'a> {
+    src:&'a str,
+    pos:usize,
+    line:u32,
+    col:u32,
+    token_start:Span,
+    trivia:Vec<Spanned<Trivia>>,
+}
 
-    if !byte.is_ascii() {return Err(TokenizerErr::NonAscii);}
-
-    let token = match byte {
-
-        b'a'..=b'z' | b'A'..=b'Z' => {
-            let name = parse_ident(byte,bytes)?;
-            match name.as_str() {
-                "local"    => Token::Local,
-                "function" => Token::Function,
-
-                "if"       => Token::If,
-                "elif"     => Token::Elif,
-                "else"     => Token::Else,
-                "while"    => Token::While,
-                "for"      => Token::For,
-                "ipairs"   => Token::IPairs,
-                "kvpairs"  => Token::KVPairs,
-                "range"    => Token::Range,
-                "in"       => Token::In,
-                "break"    => Token::Break,
-                "return"   => Token::Return,
-
-                "and"      => Token::BoolAnd,
-                "or"       => Token::BoolOr,
-                "not"      => Token::BoolNot,
-                "true"     => Token::BoolLiteral(true),
-                "flase"    => Token::BoolLiteral(false),
-                _ => Token::Ident(name.into())
-            }
-        },
-
-        b'0'..=b'9' => parse_num(byte,bytes)?,
-        b'"' => parse_str(bytes),
-
-        b'(' => Token::RoundO,
-        b')' => Token::RoundC,
-        b'{' => Token::CurlyO,
-        b'}' => Token::CurlyC,
-        b'[' => Token::SquareO,
-        b']' => Token::SquareC,
-
-        b'.' => Token::Dot,
-        b',' => Token::Comma,
-        b':' => Token::Colon,
-        b';' => Token::Endline,
-
-        b'+' => Token::Add,
-        b'-' => Token::Sub,
-        b'*' => Token::Mul,
-        b'/' => Token::Div,
-        b'%' => Token::Mod,
-        b'^' => Token::Pow,
-
-        b'!' => Token::Not,
-        b'#' => Token::Len,
-
-        b'&' => Token::And,
-        b'|' => Token::Or,
-        b'~' => Token::Xor,
-
-        b'=' => Token::Assing,
-        b'<' => Token::Less,
-        b'>' => Token::Greater,
-
-        b' ' | b'\t' | b'\n' => return parse_rec(tokens, bytes),
-        _ => return Err(TokenizerErr::InvalidSymbol(byte))
-    };
+impl<'a> Lexer<'a> {
+    fn new(src:&'a str) -> Self {
+        Self { src, pos:0, line:1, col:1, token_start:Span::new(0,0,1,1), trivia:vec![] }
+    }
 
-    if tokens.is_empty() {
-        tokens.push(token);
-        return parse_rec(tokens, bytes);
+    /// Error spanning from the start of the current token to the current position.
+    fn error(&self,err:TokenizerErr) -> Error {
+        let start = self.token_start.start;
+        let first_len = self.src[start..].chars().next().map_or(0, char::len_utf8);
+        Error::Tokenizer(err,Span { end:self.pos.max(start+first_len), ..self.token_start })
     }
 
-    let prev_token = tokens.pop().unwrap();
-    match (&token,&prev_token) {
-        (Token::Less,     Token::Less)    => tokens.push(Token::Shl),
-        (Token::Greater,  Token::Greater) => tokens.push(Token::Shr),
-        (Token::Assing,   Token::Assing)  => tokens.push(Token::Eq),
-        (Token::Greater,  Token::Assing)  => tokens.push(Token::GreaterEq),
-        (Token::Less,     Token::Assing)  => tokens.push(Token::LessEq),
-
-        (Token::Dot,      Token::Dot)     => tokens.push(Token::Concat),
-        _ => {
-            tokens.push(prev_token);
-            tokens.push(token);
-        },
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
     }
 
-    parse_rec(tokens, bytes)
-}
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
 
-fn parse_ident(first:u8,bytes:&mut Peekable<Bytes>) -> Result<String> {
-    let mut name = String::from(first as char);
-    loop {
-        let byte = match bytes.peek() {
-            Some(byte) => *byte,
-            None => return Ok(name),
-        };
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    /// Consumes `c` if it is next.
+    fn eat(&mut self,c:char) -> bool {
+        let matches = self.peek() == Some(c);
+        if matches {
+            let _ = self.next();
+        }
+        matches
+    }
 
-        if !byte.is_ascii() {return Err(TokenizerErr::NonAscii);}
+    fn parse_all(&mut self,tokens:&mut Vec<Spanned<Token>>) -> Result<()> {
+        loop {
+            loop {
+                match self.peek() {
+                    Some(' ' | '\t' | '\n') => _ = self.next(),
+                    Some('-') if self.rest().starts_with("--") => self.parse_comment()?,
+                    _ => break,
+                }
+            }
 
-        match byte {
-            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' => name.push(byte as char),
-            _ => return Ok(name),
+            self.token_start = Span::new(self.pos,self.pos,self.line,self.col);
+            let token = match self.parse_token()? {
+                Some(token) => token,
+                None => return Ok(()),
+            };
+            // `-` is only a subtraction if there is something to subtract from.
+            let token = match token {
+                Token::Sub if !tokens.last().is_some_and(|x| x.node.is_valid_end_of_expr()) => Token::Neg,
+                token => token,
+            };
+            tokens.push(Spanned::new(token,Span { end:self.pos, ..self.token_start }));
         }
+    }
 
-        let _ = bytes.next();
+    fn parse_comment(&mut self) -> Result<()> {
+        let start = Span::new(self.pos,self.pos,self.line,self.col);
+        let _ = self.next();
+        let _ = self.next();
+
+        let trivia = if self.rest().starts_with("[[") {
+            let _ = self.next();
+            let _ = self.next();
+            let text_start = self.pos;
+            let mut depth = 1;
+            loop {
+                let rest = self.rest();
+                if rest.starts_with("]]") {
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                    let _ = self.next();
+                } else if rest.starts_with("--[[") {
+                    depth += 1;
+                    (0..3).for_each(|_| _ = self.next());
+                } else if rest.is_empty() {
+                    return Err(Error::Tokenizer(TokenizerErr::UnterminatedComment,Span { end:start.start+4, ..start }));
+                }
+                let _ = self.next();
+            }
+            let text = self.src[text_start..self.pos].into();
+            let _ = self.next();
+            let _ = self.next();
+            Trivia::BlockComment(text)
+        } else {
+            // `----` and longer are plain comments, used for separator lines.
+            let doc = self.rest().starts_with('-') && !self.rest().starts_with("--");
+            if doc {
+                let _ = self.next();
+            }
+            let text_start = self.pos;
+            while self.peek().is_some_and(|x| x != '\n') {
+                let _ = self.next();
+            }
+            let text = self.src[text_start..self.pos].into();
+            if doc {Trivia::DocComment(text)} else {Trivia::Comment(text)}
+        };
+
+        self.trivia.push(Spanned::new(trivia,Span { end:self.pos, ..start }));
+        Ok(())
     }
-}
 
-fn parse_num(first:u8,bytes:&mut Peekable<Bytes>) -> Result<Token> {
-    if first == b'0' && *bytes.peek().ok_or(TokenizerErr::EarlyEOF)? == b'x' {
-        let _ = bytes.next();
-        parse_hex(bytes)
-    } else if first == b'0' && *bytes.peek().ok_or(TokenizerErr::EarlyEOF)? == b'b' {
-        let _ = bytes.next();
-        parse_binary(bytes)
-    } else {
-        parse_int(first, bytes)
+    fn parse_token(&mut self) -> Result<Option<Token>> {
+        let c = match self.next() {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+
+        Ok(Some(match c {
+
+            'r' if self.peek() == Some('"') => {
+                let _ = self.next();
+                self.parse_str(true)?
+            }
+
+            c if c.is_xid_start() => {
+                let name = self.parse_ident(c);
+                match name.as_str() {
+                    "local"    => Token::Local,
+                    "function" => Token::Function,
+
+                    "if"       => Token::If,
+                    "elif"     => Token::Elif,
+                    "else"     => Token::Else,
+                    "while"    => Token::While,
+                    "for"      => Token::For,
+                    "ipairs"   => Token::IPairs,
+                    "kvpairs"  => Token::KVPairs,
+                    "range"    => Token::Range,
+                    "in"       => Token::In,
+                    "break"    => Token::Break,
+                    "return"   => Token::Return,
+
+                    "and"      => Token::BoolAnd,
+                    "or"       => Token::BoolOr,
+                    "not"      => Token::BoolNot,
+                    "true"     => Token::BoolLiteral(true),
+                    "false"    => Token::BoolLiteral(false),
+                    "nil"      => Token::Nil,
+                    _ => Token::Ident(name.into())
+                }
+            },
+
+            '0'..='9' => self.parse_num(c)?,
+            '"' => self.parse_str(false)?,
+
+            '(' => Token::RoundO,
+            ')' => Token::RoundC,
+            '{' => Token::CurlyO,
+            '}' => Token::CurlyC,
+            '[' => Token::SquareO,
+            ']' => Token::SquareC,
+
+            '.' if self.eat('.') => {
+                if self.eat('.') {Token::VarArgs} else if self.eat('=') {Token::ConcatAssing} else {Token::Concat}
+            }
+            '.' => Token::Dot,
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            ';' => Token::Endline,
+
+            '+' => Token::Add,
+            '-' => Token::Sub,
+            '*' => Token::Mul,
+            '/' if self.eat('/') => Token::IDiv,
+            '/' => Token::Div,
+            '%' => Token::Mod,
+            '^' => Token::Pow,
+
+            '!' if self.eat('=') => Token::NotEq,
+            '!' => Token::Not,
+            '#' => Token::Len,
+
+            '&' => Token::And,
+            '|' => Token::Or,
+            '~' => Token::Xor,
+
+            '=' if self.eat('=') => Token::Eq,
+            '=' => Token::Assing,
+            '<' if self.eat('=') => Token::LessEq,
+            '<' if self.eat('<') => Token::Shl,
+            '<' => Token::Less,
+            '>' if self.eat('=') => Token::GreaterEq,
+            '>' if self.eat('>') => Token::Shr,
+            '>' => Token::Greater,
+
+            _ => return Err(self.error(TokenizerErr::InvalidSymbol(c)))
+        }))
     }
-}
 
-fn parse_hex(bytes:&mut Peekable<Bytes>) -> Result<Token> {
-    let mut x = 0i32;
-    loop {
-        let byte = *bytes.peek().ok_or(TokenizerErr::EarlyEOF)?;
-        match byte {
-            b'0'..=b'9' => x = (x << 0xF) | (byte-b'0')as i32,
-            b'a'..=b'f' => x = (x << 0xF) | (byte-b'a')as i32,
-            b'A'..=b'F' => x = (x << 0xF) | (byte-b'A')as i32,
-            _ => return Ok(Token::IntLiteral(x)),
+    fn parse_ident(&mut self,first:char) -> String {
+        let mut name = String::from(first);
+        while let Some(c) = self.peek().filter(|x| x.is_xid_continue()) {
+            name.push(c);
+            let _ = self.next();
         }
-        let _ = bytes.next();
+        name
     }
-}
 
-fn parse_binary(bytes:&mut Peekable<Bytes>) -> Result<Token> {
-    let mut x = 0i32;
-    loop {
-        let byte = *bytes.peek().ok_or(TokenizerErr::EarlyEOF)?;
-        match byte {
-            b'0' => x =  x << 1, 
-            b'1' => x = (x << 1) | 1,
-            _ => return Ok(Token::IntLiteral(x)),
+    /// Decimal, `0x` hex and `0b` binary integers, and decimal floats with an optional exponent.
+    /// Digits can be separated by `_`. Hex and binary integers can use all 32 bits.
+    fn parse_num(&mut self,first:char) -> Result<Token> {
+        let radix = match (first,self.peek()) {
+            ('0',Some('x'|'X')) => 16,
+            ('0',Some('b'|'B')) => 2,
+            _ => 10,
+        };
+
+        let mut digits = String::new();
+        if radix == 10 {
+            digits.push(first);
+            self.parse_digits(10, &mut digits)?;
+        } else {
+            let _ = self.next();
+            if self.parse_digits(radix, &mut digits)? == 0 {
+                return Err(self.error(TokenizerErr::InvalidNumber("missing digits")));
+            }
+        }
+
+        let mut is_float = false;
+        // `1..x` is a concatenation, so there has to be a digit after the point.
+        if radix == 10 && self.peek() == Some('.') && self.rest()[1..].starts_with(|x:char| x.is_ascii_digit()) {
+            let _ = self.next();
+            digits.push('.');
+            self.parse_digits(10, &mut digits)?;
+            is_float = true;
+        }
+        if radix == 10 && matches!(self.peek(),Some('e'|'E')) {
+            let _ = self.next();
+            digits.push('e');
+            if let Some(sign@('+'|'-')) = self.peek() {
+                let _ = self.next();
+                digits.push(sign);
+            }
+            if self.parse_digits(10, &mut digits)? == 0 {
+                return Err(self.error(TokenizerErr::InvalidNumber("missing exponent")));
+            }
+            is_float = true;
+        }
+
+        if self.peek().is_some_and(|x| x.is_xid_continue()) {
+            while self.peek().is_some_and(|x| x.is_xid_continue()) {
+                let _ = self.next();
+            }
+            return Err(self.error(TokenizerErr::InvalidNumber("invalid digit")));
+        }
+
+        if is_float {
+            // The standard library rounds exactly.
+            let x:f32 = digits.parse().unwrap();
+            if x.is_infinite() {
+                return Err(self.error(TokenizerErr::FloatOverflow));
+            }
+            let mantissa = digits.split('e').next().unwrap();
+            if x == 0.0 && mantissa.contains(|x:char| matches!(x,'1'..='9')) {
+                return Err(self.error(TokenizerErr::FloatUnderflow));
+            }
+            Ok(Token::FloatLiteral(x))
+        } else {
+            u32::from_str_radix(&digits, radix)
+                .map(|x| Token::IntLiteral(if radix == 10 {x as i64} else {x as i32 as i64}))
+                .map_err(|_| self.error(TokenizerErr::IntOverflow))
         }
-        let _ = bytes.next();
     }
-}
 
-fn parse_int(first:u8,bytes:&mut Peekable<Bytes>) -> Result<Token> {
-    let mut x = (first-b'0') as i32;
-    loop {
-        let byte = *bytes.peek().ok_or(TokenizerErr::EarlyEOF)?;
-        match byte {
-            b'0'..=b'9' => x = (x*10) + (byte-b'0') as i32, 
-            b'.' => {
-                let _ = bytes.next();
-                return parse_float_decimal(x, bytes);
-            },
-            _ => return Ok(Token::IntLiteral(x)),
+    /// Pushes the digits without the `_` separators and returns how many there were.
+    fn parse_digits(&mut self,radix:u32,out:&mut String) -> Result<usize> {
+        let mut count = 0;
+        let mut last = None;
+        while let Some(c) = self.peek().filter(|x| x.is_digit(radix) || *x == '_') {
+            let _ = self.next();
+            if c != '_' {
+                out.push(c);
+                count += 1;
+            }
+            last = Some(c);
+        }
+        if last == Some('_') {
+            return Err(self.error(TokenizerErr::InvalidNumber("trailing `_`")));
         }
-        let _ = bytes.next();
+        Ok(count)
     }
-}
 
-fn parse_float_decimal(whole:i32,bytes:&mut Peekable<Bytes>) -> Result<Token> {
-    let mut x = whole as f32;
-    let mut pow = 0.1;
-    loop {
-        let byte = *bytes.peek().ok_or(TokenizerErr::EarlyEOF)?;
-        match byte {
-            b'0'..b'9' => {
-                x += (byte-b'0') as f32 * pow;
-                pow *= 0.1;
-            }, 
-            _ => return Ok(Token::FloatLiteral(x)),
+    /// `"..."` strings end at the end of the line, `"""..."""` strings can span lines
+    /// and drop a newline right after the opening quotes. Raw strings (`r"..."`) have no escapes.
+    fn parse_str(&mut self,raw:bool) -> Result<Token> {
+        let triple = self.rest().starts_with("\"\"");
+        if triple {
+            let _ = self.next();
+            let _ = self.next();
+        }
+        let open = Span { end:self.pos, ..self.token_start };
+        let unterminated = || Error::Tokenizer(TokenizerErr::UnterminatedStr,open);
+        if triple && self.peek() == Some('\n') {
+            let _ = self.next();
+        }
+
+        let mut str = String::new();
+        loop {
+            match self.next().ok_or_else(unterminated)? {
+                '"' if !triple => break,
+                '"' if self.rest().starts_with("\"\"") => {
+                    let _ = self.next();
+                    let _ = self.next();
+                    break;
+                }
+                '\n' if !triple => return Err(unterminated()),
+                '\\' if !raw => str.push(self.parse_escape(open)?),
+                c => str.push(c),
+            }
         }
-        let _ = bytes.next();
+        Ok(Token::StrLiteral(str.into()))
     }
-}
 
-fn parse_str(bytes:&mut Peekable<Bytes>) -> Token {
-    let mut str = String::new();
-    loop {
-        let byte = match bytes.next() {
-            Some(byte) => byte,
-            None => return Token::StrLiteral(str.into()),
+    /// The char of the escape after a `\\`.
+    fn parse_escape(&mut self,open:Span) -> Result<char> {
+        let start = self.pos-1;
+        let Some(byte) = self.next() else {
+            return Err(Error::Tokenizer(TokenizerErr::UnterminatedStr,open));
         };
 
-        if byte == b'"' {
-            return Token::StrLiteral(str.into());
-        } else {
-            str.push(byte as char);
+        let c = match byte {
+            'n'  => Some('\n'),
+            't'  => Some('\t'),
+            'r'  => Some('\r'),
+            '0'  => Some('\0'),
+            '\\' => Some('\\'),
+            '"'  => Some('"'),
+            '\'' => Some('\''),
+
+            // Only up to 7F, like Rust, higher bytes would be invalid utf-8 on their own.
+            'x' => self.parse_hex_digits(2)
+                .filter(|x| *x <= 0x7F && self.pos-start == 4)
+                .and_then(char::from_u32),
+
+            'u' if self.peek() == Some('{') => {
+                let _ = self.next();
+                let x = self.parse_hex_digits(6);
+                let closed = self.peek() == Some('}');
+                if closed {
+                    let _ = self.next();
+                }
+                x.filter(|_| closed).and_then(char::from_u32)
+            }
+
+            _ => None,
+        };
+
+        c.ok_or_else(|| {
+            self.error(TokenizerErr::InvalidEscape(self.src[start..self.pos].into()))
+        })
+    }
+
+    /// Up to `max` hex digits, `None` if there are none.
+    fn parse_hex_digits(&mut self,max:usize) -> Option<u32> {
+        let mut x = None;
+        for _ in 0..max {
+            let Some(digit) = self.peek().and_then(|x| x.to_digit(16)) else {
+                break;
+            };
+            let _ = self.next();
+            x = Some(x.unwrap_or(0) << 4 | digit);
         }
+        x
     }
 }
 
 
+
 #[test]
 fn test() {
     match parse( "local foo = bar.baz:f(32.42 + (4<<i), \"abc\")") {
@@ -280,3 +474,292 @@ fn test() {
         Err(e) => panic!("{:?}",e)
     }
 }
+
+#[test]
+fn span_test() {
+    let tokens = parse("local x =\n  a << 2;").unwrap();
+    let spans = tokens.iter().map(|t| (t.span.start,t.span.end,t.span.line,t.span.col)).collect::<Vec<_>>();
+    assert_eq!(spans,vec![
+        (0,5,1,1),
+        (6,7,1,7),
+        (8,9,1,9),
+        (12,13,2,3),
+        (14,16,2,5),
+        (17,18,2,8),
+        (18,19,2,9),
+    ]);
+    assert_eq!(tokens[4].node,Token::Shl);
+}
+
+#[test]
+fn str_test() {
+    let str = |src:&str| match &parse(src).unwrap()[..] {
+        [Spanned { node:Token::StrLiteral(x), .. }] => x.to_string(),
+        tokens => panic!("{:?}",tokens),
+    };
+
+    assert_eq!(str(r#""a\n\t\r\0\\\"\'b""#),"a\n\t\r\0\\\"'b");
+    assert_eq!(str(r#""\x41\x7f\u{1F600}\u{e9}""#),"A\x7f\u{1F600}\u{e9}");
+    assert_eq!(str(r#""""#),"");
+    assert_eq!(str("\"\"\"\nline 1\n\"line\" 2\\n\"\"\""),"line 1\n\"line\" 2\n");
+    assert_eq!(str(r#"r"C:\dir\n""#),r"C:\dir\n");
+    assert_eq!(str("r\"\"\"a\\n\nb\"\"\""),"a\\n\nb");
+    assert_eq!(parse("r x").unwrap()[0].node,Token::Ident("r".into()));
+}
+
+#[test]
+fn str_error_test() {
+    let err = |src:&str| match parse(src) {
+        Err(Error::Tokenizer(err,span)) => (err,span.start,span.col),
+        x => panic!("{:?}",x),
+    };
+
+    assert_eq!(err("x = \"abc"),(TokenizerErr::UnterminatedStr,4,5));
+    assert_eq!(err("x = \"abc\ny\";"),(TokenizerErr::UnterminatedStr,4,5));
+    assert_eq!(err("x = \"\"\"abc\n\"\""),(TokenizerErr::UnterminatedStr,4,5));
+    assert_eq!(err("x = \"abc\\"),(TokenizerErr::UnterminatedStr,4,5));
+    assert_eq!(err("x = \"a\\qb\""),(TokenizerErr::InvalidEscape("\\q".into()),4,5));
+    assert_eq!(err("x = \"\\x80\""),(TokenizerErr::InvalidEscape("\\x80".into()),4,5));
+    assert_eq!(err("x = \"\\x4\""),(TokenizerErr::InvalidEscape("\\x4".into()),4,5));
+    assert_eq!(err("x = \"\\u{110000}\""),(TokenizerErr::InvalidEscape("\\u{110000}".into()),4,5));
+    assert_eq!(err("x = \"\\u{41\""),(TokenizerErr::InvalidEscape("\\u{41".into()),4,5));
+    assert_eq!(err("x = \"\\u{D800}\""),(TokenizerErr::InvalidEscape("\\u{D800}".into()),4,5));
+
+    let Err(Error::Tokenizer(_,span)) = parse("x = r\"abc") else { panic!() };
+    assert_eq!((span.start,span.end),(4,6));
+}
+
+#[test]
+fn comment_test() {
+    let src = "--- Adds one.\nlocal x = 1 - -1; -- one\n--[[ a --[[ nested ]] block ]]x\n---- line\n--";
+    let mut trivia = vec![];
+    let tokens = parse_with_trivia(src, &mut trivia).unwrap();
+    let tokens:Vec<Token> = tokens.into_iter().map(|x| x.node).collect();
+    assert_eq!(tokens,[
+        Token::Local,Token::Ident("x".into()),Token::Assing,Token::IntLiteral(1),
+        Token::Sub,Token::Neg,Token::IntLiteral(1),Token::Endline,Token::Ident("x".into()),
+    ]);
+
+    let trivia:Vec<(Trivia,usize,usize)> = trivia.into_iter().map(|x| (x.node,x.span.start,x.span.end)).collect();
+    assert_eq!(trivia,[
+        (Trivia::DocComment(" Adds one.".into()),0,13),
+        (Trivia::Comment(" one".into()),32,38),
+        (Trivia::BlockComment(" a --[[ nested ]] block ".into()),39,69),
+        (Trivia::Comment("-- line".into()),71,80),
+        (Trivia::Comment("".into()),81,83),
+    ]);
+
+    let Err(Error::Tokenizer(TokenizerErr::UnterminatedComment,span)) = parse("x --[[ a --[[ b ]]") else { panic!() };
+    assert_eq!((span.start,span.end),(2,6));
+}
+
+#[test]
+fn utf8_test() {
+    let tokens = parse("local größe = \"héllo 世界 👋\"; 変数 = größe;").unwrap();
+    assert_eq!(tokens[1].node,Token::Ident("größe".into()));
+    assert_eq!(tokens[3].node,Token::StrLiteral("héllo 世界 👋".into()));
+    assert_eq!(tokens[5].node,Token::Ident("変数".into()));
+    // Columns count chars, offsets count bytes.
+    let span = tokens[5].span;
+    assert_eq!((span.start,span.end,span.col),(38,44,29));
+
+    let Err(Error::Tokenizer(err,span)) = parse("x = 1 € 2") else { panic!() };
+    assert_eq!(err,TokenizerErr::InvalidSymbol('€'));
+    assert_eq!((span.start,span.end,span.col),(6,9,7));
+    assert!(parse("x = 👋;").is_err());
+}
+
+#[test]
+fn num_test() {
+    let num = |src:&str| parse(src).unwrap().into_iter().map(|x| x.node).collect::<Vec<Token>>();
+
+    assert_eq!(num("42"),[Token::IntLiteral(42)]);
+    assert_eq!(num("0x1F 0xff_ff 0XA"),[Token::IntLiteral(0x1F),Token::IntLiteral(0xFFFF),Token::IntLiteral(10)]);
+    assert_eq!(num("0xFFFFFFFF"),[Token::IntLiteral(-1)]);
+    assert_eq!(num("0b1010 0b1111_0000"),[Token::IntLiteral(10),Token::IntLiteral(0xF0)]);
+    assert_eq!(num("1_000_000 2147483647"),[Token::IntLiteral(1_000_000),Token::IntLiteral(i32::MAX as i64)]);
+    assert_eq!(num("0.9 1.5e3 1e-3 2E+2 1_0.2_5"),[
+        Token::FloatLiteral(0.9),Token::FloatLiteral(1500.0),Token::FloatLiteral(1e-3),Token::FloatLiteral(200.0),Token::FloatLiteral(10.25),
+    ]);
+    assert_eq!(num("0.1"),[Token::FloatLiteral(0.1)]);
+    assert_eq!(num("1..2"),[Token::IntLiteral(1),Token::Concat,Token::IntLiteral(2)]);
+    assert_eq!(num("0e5 1e-40"),[Token::FloatLiteral(0.0),Token::FloatLiteral(1e-40)]);
+    assert_eq!(num("-2147483648 4294967295"),[Token::Neg,Token::IntLiteral(1 << 31),Token::IntLiteral(u32::MAX as i64)]);
+    assert_eq!(num("x = 10"),[Token::Ident("x".into()),Token::Assing,Token::IntLiteral(10)]);
+
+    let err = |src:&str| match parse(src) {
+        Err(Error::Tokenizer(err,span)) => (err,span.start,span.end),
+        x => panic!("{:?}",x),
+    };
+    assert_eq!(err("x = 4294967296"),(TokenizerErr::IntOverflow,4,14));
+    assert_eq!(err("0x1_0000_0000"),(TokenizerErr::IntOverflow,0,13));
+    assert_eq!(err("1e39"),(TokenizerErr::FloatOverflow,0,4));
+    assert_eq!(err("1e-50"),(TokenizerErr::FloatUnderflow,0,5));
+    assert_eq!(err("0x"),(TokenizerErr::InvalidNumber("missing digits"),0,2));
+    assert_eq!(err("1e+"),(TokenizerErr::InvalidNumber("missing exponent"),0,3));
+    assert_eq!(err("1_000_"),(TokenizerErr::InvalidNumber("trailing `_`"),0,6));
+    assert_eq!(err("123abc"),(TokenizerErr::InvalidNumber("invalid digit"),0,6));
+    assert_eq!(err("0b102"),(TokenizerErr::InvalidNumber("invalid digit"),0,5));
+}
+
+#[test]
+fn op_test() {
+    let ops = |src:&str| parse(src).unwrap().into_iter().map(|x| x.node).collect::<Vec<Token>>();
+    let x = || Token::Ident("x".into());
+
+    assert_eq!(ops("x != x // x"),[x(),Token::NotEq,x(),Token::IDiv,x()]);
+    assert_eq!(ops("x>>x<=x>=x"),[x(),Token::Shr,x(),Token::LessEq,x(),Token::GreaterEq,x()]);
+    assert_eq!(ops("x ..= x..x"),[x(),Token::ConcatAssing,x(),Token::Concat,x()]);
+    // Operators don't merge across whitespace or comments.
+    assert_eq!(ops("< < = =>.--\n."),[Token::Less,Token::Less,Token::Assing,Token::Assing,Token::Greater,Token::Dot,Token::Dot]);
+    assert_eq!(ops("x = nil == false"),[x(),Token::Assing,Token::Nil,Token::Eq,Token::BoolLiteral(false)]);
+    assert_eq!(ops("is"),[Token::Ident("is".into())]);
+
+    assert_eq!(ops("-x - -1"),[Token::Neg,x(),Token::Sub,Token::Neg,Token::IntLiteral(1)]);
+    assert_eq!(ops("f(x)-x[1]-x"),[
+        Token::Ident("f".into()),Token::RoundO,x(),Token::RoundC,Token::Sub,
+        x(),Token::SquareO,Token::IntLiteral(1),Token::SquareC,Token::Sub,x(),
+    ]);
+    assert_eq!(ops("return -x;")[1],Token::Neg);
+    assert_eq!(ops("(-x)")[1],Token::Neg);
+    assert_eq!(ops("f(x, -x)")[4],Token::Neg);
+}
+
+#[test]
+fn round_trip_test() {
+    // No `_` arm, so a new variant doesn't compile until it is added here.
+    fn src(token:&Token) -> Option<String> {
+        Some(match token {
+            // Neither is lexed, `is` isn't a keyword until there is an operator for it.
+            Token::Invalid | Token::Is => return None,
+            Token::Local => "local".into(),
+            Token::Function => "function".into(),
+            Token::Return => "return".into(),
+            Token::If => "if".into(),
+            Token::Elif => "elif".into(),
+            Token::Else => "else".into(),
+            Token::While => "while".into(),
+            Token::For => "for".into(),
+            Token::IPairs => "ipairs".into(),
+            Token::KVPairs => "kvpairs".into(),
+            Token::Range => "range".into(),
+            Token::In => "in".into(),
+            Token::Break => "break".into(),
+            Token::Endline => ";".into(),
+            Token::Ident(x) => x.to_string(),
+            Token::Nil => "nil".into(),
+            Token::BoolLiteral(x) => x.to_string(),
+            Token::IntLiteral(x) => x.to_string(),
+            Token::FloatLiteral(x) => format!("{:e}",x),
+            Token::StrLiteral(x) => format!("{:?}",x),
+            Token::Assing => "=".into(),
+            Token::ConcatAssing => "..=".into(),
+            Token::Add => "+".into(),
+            Token::Sub => "-".into(),
+            Token::Div => "/".into(),
+            Token::Mul => "*".into(),
+            Token::IDiv => "//".into(),
+            Token::Mod => "%".into(),
+            Token::Pow => "^".into(),
+            Token::Concat => "..".into(),
+            Token::And => "&".into(),
+            Token::Or => "|".into(),
+            Token::Xor => "~".into(),
+            Token::Shr => ">>".into(),
+            Token::Shl => "<<".into(),
+            Token::BoolAnd => "and".into(),
+            Token::BoolOr => "or".into(),
+            Token::BoolNot => "not".into(),
+            Token::Not => "!".into(),
+            Token::Neg => "-".into(),
+            Token::Len => "#".into(),
+            Token::Eq => "==".into(),
+            Token::NotEq => "!=".into(),
+            Token::Less => "<".into(),
+            Token::LessEq => "<=".into(),
+            Token::Greater => ">".into(),
+            Token::GreaterEq => ">=".into(),
+            Token::RoundO => "(".into(),
+            Token::RoundC => ")".into(),
+            Token::CurlyO => "{".into(),
+            Token::CurlyC => "}".into(),
+            Token::SquareO => "[".into(),
+            Token::SquareC => "]".into(),
+            Token::Colon => ":".into(),
+            Token::Comma => ",".into(),
+            Token::Dot => ".".into(),
+            Token::VarArgs => "...".into(),
+        })
+    }
+
+    let tokens = [
+        Token::Invalid,
+        Token::Local,Token::Function,Token::Return,
+        Token::If,Token::Elif,Token::Else,
+        Token::While,Token::For,Token::IPairs,Token::KVPairs,Token::Range,Token::In,
+        Token::Break,Token::Endline,
+        Token::Ident("größe".into()),
+        Token::Nil,Token::BoolLiteral(true),Token::BoolLiteral(false),Token::IntLiteral(42),Token::FloatLiteral(0.5),
+        Token::StrLiteral("a \"b\"\n".into()),
+        Token::Assing,Token::ConcatAssing,
+        Token::Add,Token::Sub,Token::Div,Token::Mul,Token::IDiv,Token::Mod,Token::Pow,Token::Concat,
+        Token::And,Token::Or,Token::Xor,Token::Shr,Token::Shl,
+        Token::BoolAnd,Token::BoolOr,Token::BoolNot,
+        Token::Not,Token::Neg,Token::Len,
+        Token::Eq,Token::NotEq,Token::Less,Token::LessEq,Token::Greater,Token::GreaterEq,Token::Is,
+        Token::RoundO,Token::RoundC,Token::CurlyO,Token::CurlyC,Token::SquareO,Token::SquareC,
+        Token::Colon,Token::Comma,Token::Dot,Token::VarArgs,
+    ];
+
+    for token in &tokens {
+        let Some(text) = src(token) else { continue };
+        // `-` is only a subtraction after an expression.
+        let (text,idx) = if *token == Token::Sub {(format!("x {}",text),1)} else {(text,0)};
+        let lexed = parse(&text).unwrap();
+        assert_eq!(lexed.len(),idx+1,"{:?}",text);
+        assert_eq!(&lexed[idx].node,token);
+        assert_eq!((lexed[idx].span.start,lexed[idx].span.end),(text.len()-src(token).unwrap().len(),text.len()));
+    }
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn int_prop_test(x in 0..=i32::MAX) {
+        proptest::prop_assert_eq!(parse(&x.to_string()).unwrap()[0].node.clone(),Token::IntLiteral(x as i64));
+        proptest::prop_assert_eq!(parse(&format!("{:#x}",x)).unwrap()[0].node.clone(),Token::IntLiteral(x as i64));
+        proptest::prop_assert_eq!(parse(&format!("{:#b}",x)).unwrap()[0].node.clone(),Token::IntLiteral(x as i64));
+    }
+
+    #[test]
+    fn int_separator_prop_test(src in "[1-9](_?[0-9]){0,8}") {
+        let expected = src.replace('_', "").parse::<i64>().unwrap();
+        proptest::prop_assert_eq!(parse(&src).unwrap()[0].node.clone(),Token::IntLiteral(expected));
+    }
+
+    #[test]
+    fn int_overflow_prop_test(x in u32::MAX as i64 + 1..i64::MAX) {
+        proptest::prop_assert!(matches!(parse(&x.to_string()),Err(Error::Tokenizer(TokenizerErr::IntOverflow,_))));
+    }
+
+    #[test]
+    fn float_prop_test(src in "[0-9]{1,12}(\\.[0-9]{1,12})?([eE][+-]?[0-9]{1,2})?") {
+        let Ok(expected) = src.parse::<f32>() else { unreachable!() };
+        let is_float = src.contains(['.','e','E']);
+        match parse(&src) {
+            Ok(tokens) if is_float => proptest::prop_assert_eq!(tokens[0].node.clone(),Token::FloatLiteral(expected)),
+            Ok(tokens) => proptest::prop_assert_eq!(tokens[0].node.clone(),Token::IntLiteral(src.parse().unwrap())),
+            Err(Error::Tokenizer(TokenizerErr::FloatOverflow,_)) => proptest::prop_assert!(is_float && expected.is_infinite()),
+            Err(Error::Tokenizer(TokenizerErr::FloatUnderflow,_)) => proptest::prop_assert!(is_float && expected == 0.0),
+            Err(Error::Tokenizer(TokenizerErr::IntOverflow,_)) => proptest::prop_assert!(!is_float && src.parse::<u32>().is_err()),
+            Err(err) => proptest::prop_assert!(false,"{:?}",err),
+        }
+    }
+
+    #[test]
+    fn float_round_trip_prop_test(x in proptest::num::f32::POSITIVE) {
+        // `{:e}` always has an exponent, so it is a float even for whole numbers.
+        let src = format!("{:e}",x);
+        proptest::prop_assert_eq!(parse(&src).unwrap()[0].node.clone(),Token::FloatLiteral(x));
+    }
+}
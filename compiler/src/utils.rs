@@ -1,26 +1,8 @@
-use crate::tokenizer::Token;
+use crate::{err::{Error, ParserErr}, span::{Span, Spanned}, tokenizer::Token};
 
 impl Token {
-    pub fn find(tokens:&[Token],target:&Token) -> Option<usize> {
-        for (i,token) in tokens.iter().enumerate() {
-            if token == target {return Some(i);}
-        }
-        None
-    }
-
-    pub fn find_outside_of_brackets(tokens:&[Token],target:&Token) -> Option<usize> {
-        let mut depth = 0;
-        for (i,token) in tokens.iter().enumerate() {
-            depth += token.brack_depth();
-            if token == target && depth <= 0 {
-                return Some(i);
-            }
-        }
-        None
-    }
-
-    pub fn find_matching_bracket(tokens:&[Token],start:usize) -> Option<usize> {
-        let open = tokens[start].clone();
+    pub fn find_matching_bracket(tokens:&[Spanned<Token>],start:usize) -> Option<usize> {
+        let open = tokens.get(start)?.node.clone();
         let close = match open {
             Token::RoundO => Token::RoundC,
             Token::CurlyO => Token::CurlyC,
@@ -30,8 +12,8 @@ impl Token {
 
         let mut depth = 0;
         for (i,token) in tokens[start..].iter().enumerate() {
-            if token == &open  {depth += 1}
-            if token == &close {depth -= 1}
+            if token.node == open  {depth += 1}
+            if token.node == close {depth -= 1}
             if depth == 0 {
                 return Some(i+start);
             }
@@ -39,24 +21,20 @@ impl Token {
         None
     }
 
-    pub fn find_matching_bracket_rev(tokens:&[Token]) -> Option<usize> {
-        let open = tokens.last().unwrap().clone();
-        let close = match open {
-            Token::RoundC => Token::RoundO,
-            Token::CurlyC => Token::CurlyO,
-            Token::SquareC => Token::SquareO,
-            _ => return None,
-        };
+    /// Error for the token at `i`, or for running out of tokens if `i` is past the end.
+    pub fn unexpected(tokens:&[Spanned<Token>],i:usize,expected:&'static str) -> Error {
+        match tokens.get(i) {
+            Some(token) => Error::Parser(ParserErr::UnexpectedToken{found:token.node.clone(),expected},token.span),
+            None => Error::Parser(ParserErr::UnexpectedEnd{expected},tokens.last().map(|x| x.span).unwrap_or_default()),
+        }
+    }
 
-        let mut depth = 0;
-        for (i,token) in tokens.iter().enumerate().rev() {
-            if token == &open  {depth += 1}
-            if token == &close {depth -= 1}
-            if depth == 0 {
-                return Some(i);
-            }
+    /// Span covering every token in the slice.
+    pub fn span_of(tokens:&[Spanned<Token>]) -> Span {
+        match (tokens.first(),tokens.last()) {
+            (Some(first),Some(last)) => first.span.to(last.span),
+            _ => Span::default(),
         }
-        None
     }
 
     pub fn brack_depth(&self) -> i32 {
@@ -66,30 +44,4 @@ impl Token {
             _ => 0,
         }
     }
-
-    pub fn parse_list_of_idents(tokens:&[Token]) -> Vec<Box<str>> {
-        let mut ident_iter = tokens.into_iter();
-        let mut idents:Vec<Box<str>> = vec![];
-        loop {
-            let name = match ident_iter.next() {
-                Some(token) => match token {
-                    Token::Ident(name) => name,
-                    _ => panic!("invalid arg {:?}",token)
-                },
-
-                None => break
-            };
-            idents.push(name.clone());
-
-            match ident_iter.next() {
-                Some(token) => match token {
-                    Token::Comma => {},
-                    _ => panic!("expected comma, got {:?}",token)
-                },
-
-                None => break
-            }
-        }
-        idents
-    }
 }
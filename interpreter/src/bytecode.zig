@@ -2,6 +2,7 @@ const std = @import("std");
 const Vm = @import("vm.zig").Vm;
 const Var = @import("var.zig").Var;
 const Str = @import("str.zig").Str;
+const FormatErr = @import("err.zig").FormatErr;
 
 pub const ByteCodeType = enum(u8) {
     load_nil           = 0,
@@ -14,6 +15,7 @@ pub const ByteCodeType = enum(u8) {
     load  = 7,
     write = 8,
     pop   = 43,
+    dup   = 53,
 
     add    =  9,
     sub    = 10,
@@ -47,7 +49,9 @@ pub const ByteCodeType = enum(u8) {
     set        = 46,
     set_pop    = 47,
     push       = 48,
+    push_all   = 55,
     get_method = 49,
+    next       = 52,
 
 
     closure = 17,
@@ -57,14 +61,31 @@ pub const ByteCodeType = enum(u8) {
     bind_upval = 20,
     get_upval  = 21,
     set_upval  = 22,
+    close      = 58,
+
+    load_var_args = 54,
+
+    get_global = 50,
+    set_global = 51,
 
     jump       = 23,
     jump_true  = 24,
     jump_false = 25,
 
+    jump_long       = 59,
+    jump_true_long  = 60,
+    jump_false_long = 61,
+
+    extend_arg = 62,
+
     halt = 30,
 };
 
+/// Argument count of `call` and `ret` for a variable number of values, the count is on top of the stack.
+pub const var_count:u16 = std.math.maxInt(u16);
+/// Result count of `call` that keeps all results and pushes their count after them.
+pub const var_ret_count:u16 = std.math.maxInt(u16);
+
 pub const ByteCode = union(ByteCodeType) {
     const Type = ByteCodeType;
 
@@ -79,6 +100,7 @@ pub const ByteCode = union(ByteCodeType) {
     load:u16,
     write:u16,
     pop:void,
+    dup:void,
 
     add:void,
     sub:void,
@@ -112,24 +134,43 @@ pub const ByteCode = union(ByteCodeType) {
     set:void,
     set_pop:void,
     push:void,
+    /// Pops a count and that many values and pushes them to the table below them.
+    push_all:void,
+    /// Replaces the table on top with its method of the name.
     get_method:u16,
+    /// Advances the iterator in the slots `i` (table), `i+1` (key) and `i+2` (value).
+    next:u16,
 
-    closure: packed struct {
-        upval_cap:u8,
-        arg_count:u8
-    },
+    /// The index of a prototype.
+    closure:u16,
 
+    /// The argument count, the result count is in the next word.
     call:u16,
-    ret:void,
+    ret:u16,
 
     bind_upval:u16,
     get_upval:u16,
     set_upval:u16,
+    /// Closes the open upvalues of the slot and the ones above it.
+    close:u16,
+
+    load_var_args:u16,
+
+    get_global:u16,
+    set_global:u16,
 
     jump:i16,
     jump_true:i16,
     jump_false:i16,
 
+    /// The `i32` offset is in the next word.
+    jump_long:void,
+    jump_true_long:void,
+    jump_false_long:void,
+
+    /// The upper half of the next instruction's operand.
+    extend_arg:u16,
+
     halt:void,
 
     pub fn asInt(self:*const ByteCode) u32 {
@@ -143,60 +184,211 @@ pub const ByteCode = union(ByteCodeType) {
     }
 };
 
+/// Where a closure finds an upvalue when it is created.
+pub const UpvalDesc = union(enum) {
+    /// A slot of the function creating the closure.
+    local:u32,
+    /// An upvalue of the function creating the closure.
+    upval:u32,
+};
+
+pub const Proto = struct {
+    name:[]const u8,
+    arg_count:u16,
+    var_args:bool,
+    max_stack:u32,
+    upvals:[]UpvalDesc,
+    code:[]u32,
+};
+
+const SectionKind = enum(u32) {
+    names  = 0,
+    main   = 1,
+    protos = 2,
+};
+
+const magic = "MUNA";
+const format_version:u16 = 2;
+const header_len = 16;
+const section_entry_len = 12;
+
+const native_endian:u8 = if (@import("builtin").cpu.arch.endian() == .little) 0 else 1;
+
+/// Reads the little endian fields of a `.lout` file.
+const Reader = struct {
+    bytes:[]const u8,
+    pos:usize = 0,
+
+    fn take(self:*Reader,n:usize) FormatErr![]const u8 {
+        if (self.bytes.len-self.pos < n) {
+            return error.truncated;
+        }
+        const slice = self.bytes[self.pos..self.pos+n];
+        self.pos += n;
+        return slice;
+    }
+
+    fn int(self:*Reader,comptime T:type) FormatErr!T {
+        const bytes = try self.take(@sizeOf(T));
+        return std.mem.readInt(T, bytes[0..@sizeOf(T)], .little);
+    }
+
+    fn str(self:*Reader) FormatErr![]const u8 {
+        const rest = self.bytes[self.pos..];
+        const len = std.mem.indexOfScalar(u8, rest, 0) orelse return error.truncated;
+        self.pos += len+1;
+        return rest[0..len];
+    }
+};
+
 pub const Program = struct {
     const Self = @This();
 
-    list:std.ArrayList(u32),
+    bytes:[]u8,
     ip:[*]const u32,
     name_table:[]Var,
+    main:Proto,
+    protos:[]Proto,
 
     pub fn init(path:[]const u8) !Self {
-        const file = try std.fs.cwd().openFile(path, .{});
-        defer file.close();
-        const reader = file.reader();
+        const bytes = try std.fs.cwd().readFileAlloc(Vm.gpa, path, std.math.maxInt(u32));
+        errdefer Vm.gpa.free(bytes);
+        return initBytes(bytes);
+    }
+
+    /// Checks the header and the checksum and loads the sections, takes ownership of `bytes` if it succeeds.
+    pub fn initBytes(bytes:[]u8) FormatErr!Self {
+        if (bytes.len < magic.len or !std.mem.eql(u8, bytes[0..magic.len], magic)) {
+            return error.badMagic;
+        }
+        if (bytes.len < header_len) {
+            return error.truncated;
+        }
+
+        var header = Reader{.bytes = bytes[0..header_len], .pos = magic.len};
+        if (try header.int(u16) != format_version) {
+            return error.unsupportedVersion;
+        }
+        if (try header.int(u8) != native_endian) {
+            return error.wrongEndianness;
+        }
+        const section_count = try header.int(u8);
+        const crc = try header.int(u32);
+        const payload_len = try header.int(u32);
+
+        if (bytes.len < header_len+@as(usize,payload_len)) {
+            return error.truncated;
+        }
+        if (bytes.len != header_len+@as(usize,payload_len)) {
+            return error.trailingBytes;
+        }
+        if (std.hash.Crc32.hash(bytes[header_len..]) != crc) {
+            return error.checksumMismatch;
+        }
 
-        const name_table = loadNameTable(reader);
-        const list = loadByteCode(reader);
+        const table_end = header_len + @as(usize,section_count)*section_entry_len;
+        var table = Reader{.bytes = bytes, .pos = header_len};
+        var sections = std.EnumArray(SectionKind, ?[]const u8).initFill(null);
+        for (0..section_count) |_| {
+            const kind = std.meta.intToEnum(SectionKind, try table.int(u32)) catch return error.unknownSection;
+            const offset = try table.int(u32);
+            const len = try table.int(u32);
+
+            if (offset < table_end) {
+                return error.badSectionTable;
+            }
+            if (offset > bytes.len or bytes.len-offset < len) {
+                return error.truncated;
+            }
+            sections.set(kind, bytes[offset..offset+len]);
+        }
+
+        const name_table = try loadNameTable(sections.get(.names) orelse return error.missingSection);
+        var main_reader = Reader{.bytes = sections.get(.main) orelse return error.missingSection};
+        const main = try loadProto(&main_reader);
+        const protos = try loadProtos(sections.get(.protos) orelse return error.missingSection);
 
         return .{
-           .name_table = name_table,
-           .list = list,
-           .ip = @ptrCast(list.items.ptr),
+            .bytes = bytes,
+            .ip = main.code.ptr,
+            .name_table = name_table,
+            .main = main,
+            .protos = protos,
         };
     }
 
-    pub fn loadNameTable(reader:anytype) []Var {
-        const name_count = reader.readInt(u16, .little)  catch unreachable;
-        var name_table = Vm.page_a.alloc(Var, name_count) catch unreachable;
-        var buffer:[std.math.maxInt(u16)]u8 = undefined;
+    fn loadNameTable(section:[]const u8) FormatErr![]Var {
+        var reader = Reader{.bytes = section};
+        const name_count = try reader.int(u32);
+        const name_table = Vm.page_a.alloc(Var, name_count) catch unreachable;
 
         for (0..name_count) |i| {
-            const slice = reader.readUntilDelimiter(buffer[0..], 0) catch unreachable;
-            const str = Str.init(slice);
-            std.debug.print("name {}:{c} \n", .{i,str.asSlice()});
-            name_table[i] = Var.from(str);
+            name_table[i] = Var.from(Str.init(try reader.str()));
         }
 
         return name_table;
     }
 
-    pub fn loadByteCode(reader:anytype) std.ArrayList(u32) {
-        var bytes = std.ArrayListAligned(u8, 4).init(Vm.gpa);
-        reader.readAllArrayListAligned(4, &bytes, std.math.maxInt(usize)) catch unreachable;
+    fn loadProtos(section:[]const u8) FormatErr![]Proto {
+        var reader = Reader{.bytes = section};
+        const proto_count = try reader.int(u32);
+        const protos = Vm.gpa.alloc(Proto, proto_count) catch unreachable;
+
+        for (protos) |*proto| {
+            proto.* = try loadProto(&reader);
+        }
+
+        return protos;
+    }
+
+    /// The code words are copied, because they aren't necessarily aligned in the file.
+    fn loadProto(reader:*Reader) FormatErr!Proto {
+        const name = try reader.str();
+        const arg_count = try reader.int(u16);
+        const var_args = try reader.int(u8) != 0;
+        const max_stack = try reader.int(u32);
+
+        const upvals = Vm.gpa.alloc(UpvalDesc, try reader.int(u32)) catch unreachable;
+        for (upvals) |*upval| {
+            const kind = try reader.int(u8);
+            const idx = try reader.int(u32);
+            upval.* = switch (kind) {
+                0 => .{.local = idx},
+                1 => .{.upval = idx},
+                else => return error.invalidUpvalKind,
+            };
+        }
+
+        const code_len = try reader.int(u32);
+        const code = Vm.gpa.alloc(u32, code_len) catch unreachable;
+        @memcpy(std.mem.sliceAsBytes(code), try reader.take(@as(usize,code_len)*4));
 
         return .{
-            .items = @ptrCast(bytes.items),
-            .capacity = bytes.capacity/4,
-            .allocator = Vm.gpa,
+            .name = name,
+            .arg_count = arg_count,
+            .var_args = var_args,
+            .max_stack = max_stack,
+            .upvals = upvals,
+            .code = code,
         };
     }
 
+    fn deinitProto(proto:Proto) void {
+        Vm.gpa.free(proto.upvals);
+        Vm.gpa.free(proto.code);
+    }
+
     pub fn deinit(self:*Self) void {
-        self.list.deinit();
+        deinitProto(self.main);
+        for (self.protos) |proto| {
+            deinitProto(proto);
+        }
+        Vm.gpa.free(self.protos);
         for (self.name_table) |str| {
             str.as(Str).deinit();
         }
         Vm.page_a.free(self.name_table);
+        Vm.gpa.free(self.bytes);
     }
 
     pub fn next(self: *Self,comptime T:type) T {
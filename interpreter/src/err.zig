@@ -7,6 +7,20 @@ pub const ReturnCode = error {
     todo,
 };
 
+/// A `.lout` file that can't be loaded.
+pub const FormatErr = error {
+    badMagic,
+    unsupportedVersion,
+    wrongEndianness,
+    truncated,
+    trailingBytes,
+    checksumMismatch,
+    unknownSection,
+    badSectionTable,
+    missingSection,
+    invalidUpvalKind,
+};
+
 const ErrType = enum {
     invalidIdx,
     opTypeErr,
@@ -31,7 +45,7 @@ pub const Err = union(ErrType) {
 
     unaryTypeErr: struct {
         op:enum {
-            call,method,neg,len,bin_not,bool_not
+            call,method,iter,neg,len,bin_not,bool_not
         },
         ty:Var.Type
     },
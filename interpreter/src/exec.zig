@@ -2,6 +2,7 @@ const std = @import("std");
 const Var = @import("var.zig").Var;
 const Vm = @import("vm.zig").Vm;
 const ByteCode = @import("bytecode.zig").ByteCode;
+const var_count = @import("bytecode.zig").var_count;
 const Str = @import("str.zig").Str;
 const ops = @import("ops.zig");
 const Err = @import("err.zig").Err;
@@ -9,18 +10,35 @@ const Func = @import("func.zig").Func;
 const Table = @import("table.zig").Table;
 
 
-pub fn exec(instr:ByteCode,vm: *Vm) !void {
+fn jump(vm: *Vm,offset:i32) void {
+    if (offset < 0) {
+        vm.program.ip -= @abs(offset);
+    } else {
+        vm.program.ip += @as(usize,@intCast(offset));
+    }
+}
+
+pub fn exec(first:ByteCode,vm: *Vm) !void {
+    // Operands that don't fit into 16 bits get their upper half from extend_arg prefixes.
+    var instr = first;
+    var ext:u32 = 0;
+    while (instr == .extend_arg) {
+        ext = @as(u32,instr.extend_arg) << 16;
+        instr = vm.program.next(ByteCode);
+    }
+
     switch (instr) {
         .load_nil   => vm.push(Var.nil_val),
         .load_true  => vm.push(Var.true_val),
         .load_false => vm.push(Var.false_val),
         .load_int   => vm.push(Var.from(vm.program.next(i32))),
         .load_float => vm.push(Var.from(vm.program.next(f32))),
-        .load_str => |i| vm.push(vm.program.name_table[i]),
+        .load_str => |i| vm.push(vm.program.name_table[ext|i]),
 
-        .load  => |i| vm.push(vm.bp[i]),
-        .write => |i| vm.bp[i] = vm.pop(),
+        .load  => |i| vm.push(vm.bp[ext|i]),
+        .write => |i| vm.bp[ext|i] = vm.pop(),
         .pop => _ = vm.pop(),
+        .dup => vm.push(vm.top().*),
 
         .add    => try vm.binaryOp(ops.add),
         .sub    => try vm.binaryOp(ops.sub),
@@ -49,11 +67,11 @@ pub fn exec(instr:ByteCode,vm: *Vm) !void {
         .bool_not => vm.top().* = Var.from(try ops.boolNot(vm.top().*)),
         .len      => try vm.unaryOp(ops.len),
 
-        .new_table => |cap| vm.push(Var.from(Table.init(cap))),
+        .new_table => |cap| vm.push(Var.from(Table.init(ext|cap))),
 
         .get => try vm.binaryOp(ops.get),
         .get_method => |i| {
-            const k = vm.program.name_table[i];
+            const k = vm.program.name_table[ext|i];
             if (vm.top().tag() != .table) {
                 Err.global = Err{.unaryTypeErr = .{
                     .op = .method,
@@ -64,7 +82,7 @@ pub fn exec(instr:ByteCode,vm: *Vm) !void {
 
             if (vm.top().as(*Table).getMetaTable()) |mt| {
                 if (mt.getNoValidate(k)) |m| {
-                    vm.push(m);
+                    vm.top().* = m;
                     return;
                 }
             }
@@ -110,19 +128,43 @@ pub fn exec(instr:ByteCode,vm: *Vm) !void {
             const x = vm.pop();
             vm.top().as(*Table).pushUnsafe(x);
         },
+        .push_all => {
+            const count:usize = @intCast(vm.pop().as(i32));
+            const values = (vm.sp-count)[0..count];
+            const t = (vm.sp-count-1)[0].as(*Table);
+            for (values) |x| {
+                t.push(x);
+            }
+            vm.sp -= count;
+        },
+
+        .next => |i| {
+            const slot = vm.bp+(ext|i);
+            if (slot[0].tag() != .table) {
+                Err.global = .{.unaryTypeErr = .{
+                    .op = .iter,
+                    .ty = slot[0].tag(),
+                }};
+                return error.panic;
+            }
 
-        .closure => |arg| {
-            const offset = vm.program.next(u32);
-            std.debug.print("{}\n", .{offset});
-            const ptr = vm.program.ip + offset;
-            const func = Func.init(ptr,arg.arg_count,arg.upval_cap);
-            vm.push(Var.from(func));
+            if (slot[0].as(*Table).next(slot[1])) |pair| {
+                slot[1] = pair[0];
+                slot[2] = pair[1];
+                vm.push(Var.true_val);
+            } else {
+                vm.push(Var.false_val);
+            }
         },
 
+        .closure => |i| vm.push(Var.from(Func.init(&vm.program.protos[ext|i],vm))),
+
         .call => |arg_count| {
+            const ret_count:u16 = @truncate(vm.program.next(u32));
             const x = vm.pop();
+            const count:u32 = if (arg_count == var_count) @intCast(vm.pop().as(i32)) else arg_count;
             switch (x.tag()) {
-                .func => try x.as(*Func).call(@intCast(arg_count),vm),
+                .func => try x.as(*Func).call(count,ret_count,vm),
                 else => {
                     Err.global = .{.unaryTypeErr = .{
                         .op = .call,
@@ -134,19 +176,48 @@ pub fn exec(instr:ByteCode,vm: *Vm) !void {
             } 
         },
 
-        .ret => try Func.ret(vm),
+        .ret => |count| try Func.ret(count,vm),
 
         .bind_upval => |i| {
             const x = vm.pop();
-            vm.top().as(*Func).upvals[i] = x;
+            vm.top().as(*Func).upvals[ext|i].ptr.* = x;
         },
 
-        .get_upval => |i| vm.push(vm.upval_ctx[i]),
-        .set_upval => |i| vm.upval_ctx[i] = vm.pop(),
+        .get_upval => |i| vm.push(vm.upval_ctx[ext|i].ptr.*),
+        .set_upval => |i| vm.upval_ctx[ext|i].ptr.* = vm.pop(),
+        .close => |i| vm.closeUpvals(vm.bp+(ext|i)),
+
+        .load_var_args => |n| {
+            if (n == var_count) {
+                for (vm.var_args) |x| {
+                    vm.push(x);
+                }
+                vm.push(Var.from(@as(i32,@intCast(vm.var_args.len))));
+            } else {
+                for (0..n) |i| {
+                    vm.push(if (i < vm.var_args.len) vm.var_args[i] else Var.nil_val);
+                }
+            }
+        },
+
+        .get_global => |i| vm.push(vm.globals.getNoValidate(vm.program.name_table[ext|i]) orelse Var.nil_val),
+        .set_global => |i| vm.globals.setNoValidate(vm.program.name_table[ext|i], vm.pop()),
+
+        .jump => |offset| jump(vm, offset),
+        .jump_true  => |offset| if ( try ops.truthy(vm.pop())) jump(vm, offset),
+        .jump_false => |offset| if (!try ops.truthy(vm.pop())) jump(vm, offset),
+
+        .jump_long => jump(vm, vm.program.next(i32)),
+        .jump_true_long => {
+            const offset = vm.program.next(i32);
+            if (try ops.truthy(vm.pop())) jump(vm, offset);
+        },
+        .jump_false_long => {
+            const offset = vm.program.next(i32);
+            if (!try ops.truthy(vm.pop())) jump(vm, offset);
+        },
 
-        .jump => |offset| vm.program.ip += @bitCast(@as(i64,offset)),
-        .jump_true  => |offset| if ( try ops.truthy(vm.pop())) {vm.program.ip += @bitCast(@as(i64,offset));},
-        .jump_false => |offset| if (!try ops.truthy(vm.pop())) {vm.program.ip += @bitCast(@as(i64,offset));},
+        .extend_arg => unreachable,
 
         .halt => return error.halt,
 
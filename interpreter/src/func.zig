@@ -3,44 +3,67 @@ const Var = @import("var.zig").Var;
 const Vm = @import("vm.zig").Vm;
 const Err = @import("err.zig").Err;
 const ReturnCode = @import("err.zig").ReturnCode;
+const var_count = @import("bytecode.zig").var_count;
+const Proto = @import("bytecode.zig").Proto;
+
+/// A captured local. While the local is on the stack `ptr` points at its slot,
+/// once it is closed the value moves into `closed`.
+pub const Upval = struct {
+    ptr:*Var,
+    closed:Var,
+    /// The next open upvalue further down the stack.
+    next:?*Upval,
+};
 
 pub const Func = struct {
     const Self = @This();
 
     ptr:[*]const u32,
-    arg_count:u8,
+    arg_count:u16,
+    var_args:bool,
     is_callback:bool,
     marked:bool,
 
-    upvals:[*]Var,
-    upval_count:u16,
+    upvals:[*]*Upval,
+    upval_count:u32,
 
     pub const CallStackEntry = struct {
         bp:[*]Var,
         ip:[*]const u32,
-        upval_ctx:[*]Var,
+        upval_ctx:[*]*Upval,
+        var_args:[]const Var,
+        ret_count:u16,
     };
 
     pub const CallStack = std.ArrayList(CallStackEntry);
 
-    pub fn init(ptr:[*]const u32,arg_count:u8,upval_cap:u8) *Self {
-        const upvals = Vm.gpa.alloc(Var, upval_cap) catch unreachable;
-        var self = Vm.gpa.create(Func) catch unreachable;
+    /// Creates a closure of `proto`, capturing its upvalues from the running function.
+    pub fn init(proto:*const Proto,vm:*Vm) *Self {
+        const upvals = Vm.gpa.alloc(*Upval, proto.upvals.len) catch unreachable;
+        for (upvals, proto.upvals) |*upval,desc| {
+            upval.* = switch (desc) {
+                .local => |slot| vm.captureUpval(&vm.bp[slot]),
+                .upval => |i| vm.upval_ctx[i],
+            };
+        }
 
-        self.ptr = ptr;
-        self.arg_count = arg_count;
+        const self = Vm.gpa.create(Func) catch unreachable;
+        self.ptr = proto.code.ptr;
+        self.arg_count = proto.arg_count;
+        self.var_args = proto.var_args;
         self.marked = false;
         self.is_callback = false;
         self.upvals = upvals.ptr;
-        self.upval_count = upval_cap;
+        self.upval_count = @intCast(upvals.len);
         return self;
     }
 
-    pub fn initCallBack(func:*const fn(*Vm,[]Var) ReturnCode!Var,arg_count:u8) *Self {
+    pub fn initCallBack(func:*const fn(*Vm,[]Var) ReturnCode!Var,arg_count:u16) *Self {
         var self = Vm.gpa.create(Func) catch unreachable;
 
         self.ptr = @ptrCast(@alignCast(func));
         self.arg_count = arg_count;
+        self.var_args = false;
         self.marked = false;
         self.is_callback = true;
         self.upvals = undefined;
@@ -127,47 +150,65 @@ pub const Func = struct {
             }
         };
 
-        return Func.initCallBack(Wraper.wraped, info.params.len);
+        return Func.initCallBack(Wraper.wraped, info.params.len-1);
     }
 
-    pub fn call(self: *Self, arg_count:u8, vm: *Vm) !void {
-        if (arg_count > self.arg_count) {
-            for (0..arg_count-self.arg_count) |_| {
+    /// Calls with the `arg_count` arguments on top of the stack, below them is the return slot.
+    pub fn call(self: *Self, arg_count:u32, ret_count:u16, vm: *Vm) !void {
+        var var_args:[]const Var = &.{};
+        if (arg_count < self.arg_count) {
+            for (arg_count..self.arg_count) |_| {
                 vm.push(Var.nil_val);
             }
-        } else if (arg_count < self.arg_count) {
-            vm.sp -= self.arg_count-arg_count;
+        } else {
+            if (self.var_args) {
+                var_args = Vm.gpa.dupe(Var, (vm.sp-arg_count+self.arg_count)[0..arg_count-self.arg_count]) catch unreachable;
+            }
+            vm.sp -= arg_count-self.arg_count;
         }
+        const bp = vm.sp-self.arg_count-1;
 
         if (!self.is_callback) {
             vm.call_stack.append(.{
                 .ip = vm.program.ip,
                 .bp = vm.bp,
-                .upval_ctx = vm.upval_ctx
+                .upval_ctx = vm.upval_ctx,
+                .var_args = vm.var_args,
+                .ret_count = ret_count,
             }) catch unreachable;
 
             vm.program.ip = self.ptr;
-            vm.bp = vm.sp-self.arg_count-1;
+            vm.bp = bp;
             vm.upval_ctx = self.upvals;
+            vm.var_args = var_args;
         } else {
             const func: *const fn(*Vm,[]Var) ReturnCode!Var = @ptrCast(self.ptr);
-            vm.bp[0] = try func(vm, (vm.bp+1)[0..arg_count]);
-            vm.program.ip -= arg_count;
+            bp[0] = try func(vm, (bp+1)[0..self.arg_count]);
+            vm.sp = bp+1;
+            vm.adjustResults(1, ret_count);
         }
     }
 
-    pub fn ret(vm: *Vm) !void {
-        if (vm.call_stack.pop()) |e| {
-            vm.sp = vm.bp+1;
-            vm.bp = e.bp;
-            vm.program.ip = e.ip;
-            vm.upval_ctx = e.upval_ctx;
-        } else {
-            return error.halt;
-        }
+    /// Moves the top `count` values into the return slot and the ones after it.
+    pub fn ret(count:u16, vm: *Vm) !void {
+        const n:usize = if (count == var_count) @intCast(vm.pop().as(i32)) else count;
+        const e = vm.call_stack.pop() orelse return error.halt;
+
+        std.mem.copyForwards(Var, vm.bp[0..n], (vm.sp-n)[0..n]);
+        vm.sp = vm.bp+n;
+        vm.adjustResults(n, e.ret_count);
+
+        Vm.gpa.free(vm.var_args);
+        vm.bp = e.bp;
+        vm.program.ip = e.ip;
+        vm.upval_ctx = e.upval_ctx;
+        vm.var_args = e.var_args;
     }
 
     pub fn deinit(self:*Self) void {
+        if (!self.is_callback) {
+            Vm.gpa.free(self.upvals[0..self.upval_count]);
+        }
         Vm.gpa.destroy(self);
     }
 
@@ -184,7 +225,16 @@ test "Callback" {
     defer f.deinit();
     defer vm.deinit();
 
+    vm.push(Var.nil_val);
     vm.push(Var.from(32));
-    try f.call(1, &vm);
+    try f.call(1, 1, &vm);
     try std.testing.expectEqual(33, vm.top().as(i32));
+
+    vm.push(Var.nil_val);
+    vm.push(Var.from(1));
+    vm.push(Var.from(2));
+    try f.call(2, 3, &vm);
+    try std.testing.expectEqual(Var.nil_val, vm.pop());
+    try std.testing.expectEqual(Var.nil_val, vm.pop());
+    try std.testing.expectEqual(2, vm.pop().as(i32));
 }
\ No newline at end of file
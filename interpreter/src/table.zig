@@ -55,8 +55,7 @@ pub const Table = struct {
     }
 
     fn reallocArr(self: *Self,new_cap:u32) void {
-        Vm.gpa.free(self.arrSlice());
-        self.arr = (Vm.gpa.alloc(Var, new_cap) catch unreachable).ptr;
+        self.arr = (Vm.gpa.realloc(self.arrSlice(), new_cap) catch unreachable).ptr;
         self.arr_cap = new_cap;
     }
 
@@ -131,7 +130,7 @@ pub const Table = struct {
 
     pub fn push(self:*Self, x:Var) void {
         if (self.arr_len == self.arr_cap) {
-            self.reallocArr(self.arr_cap*2);
+            self.reallocArr(@max(self.arr_cap*2, 4));
         }
         self.pushUnsafe(x);
     }
@@ -141,6 +140,30 @@ pub const Table = struct {
         self.arr_len += 1;
     }
 
+    /// The pair after `k`, the array part comes first. The iteration starts with nil.
+    pub fn next(self:*const Self,k:Var) ?struct{Var,Var} {
+        var i:u32 = 0;
+        if (k.tag() == .int and k.as(i32) >= 0 and k.as(i32) < self.arr_len) {
+            i = @intCast(k.as(i32)+1);
+        } else if (k.tag() != .nil) {
+            var iter = self.map.iterator();
+            while (iter.next()) |e| {
+                if (hashEq(e.key_ptr.*, k)) {
+                    break;
+                }
+            }
+            const e = iter.next() orelse return null;
+            return .{e.key_ptr.*, e.value_ptr.*};
+        }
+
+        if (i < self.arr_len) {
+            return .{Var.from(@as(i32,@intCast(i))), self.arr[i]};
+        }
+        var iter = self.map.iterator();
+        const e = iter.next() orelse return null;
+        return .{e.key_ptr.*, e.value_ptr.*};
+    }
+
     pub fn hash(k:Var) u32 {
         const bool_hash:u32 = 0xaaaaaaaa; 
         switch (k.tag()) {
@@ -5,6 +5,7 @@ const Str = @import("str.zig").Str;
 const ByteCode = @import("bytecode.zig").ByteCode;
 const Table = @import("table.zig").Table;
 const Var = @import("var.zig").Var;
+const Func = @import("func.zig").Func;
 
 test "compat" {
     const p = try Program.init("tests/compat.lout");
@@ -68,6 +69,159 @@ test "tables get set" {
     try std.testing.expectEqual(10, (try y.get(Var.from(Str.init("x")))).?.as(i32));
 }
 
+test "for" {
+    const p = try Program.init("tests/for.lout");
+    var vm = Vm.init(p);
+    defer vm.deinit();
+    try vm.execUntilHaltDebug();
+
+    // The loop variables and locals of every loop are popped, only the main chunk's locals are left.
+    try std.testing.expectEqual(.func, vm.pop().tag());
+    try std.testing.expectEqual(3, vm.pop().as(i32));
+    // `kvpairs` goes through the array part in order, then the map.
+    try std.testing.expectEqual(1234, vm.pop().as(i32));
+    try std.testing.expectEqual((1+2+3) + (0+1+2+3) + (100+200+300), vm.pop().as(i32));
+    try std.testing.expectEqual(.table, vm.pop().tag());
+}
+
+test "and/or" {
+    const p = try Program.init("tests/and_or.lout");
+    var vm = Vm.init(p);
+    defer vm.deinit();
+    try vm.execUntilHaltDebug();
+
+    // The result is the operand that decided it, not a bool.
+    try std.testing.expectEqual(2, vm.pop().as(i32));
+    try std.testing.expectEqual(3, vm.pop().as(i32));
+    try std.testing.expectEqual(Var.false_val, vm.pop());
+    try std.testing.expectEqual(2, vm.pop().as(i32));
+    try std.testing.expectEqual(Var.nil_val, vm.pop());
+    try std.testing.expectEqual(Var.nil_val, vm.pop());
+    try std.testing.expectEqual(.func, vm.pop().tag());
+    // `t.x`, `hit(1)` and `hit(4)` are never evaluated.
+    try std.testing.expectEqual(3, vm.pop().as(i32));
+}
+
+fn setMetaTable(_:*Vm, t:*Table, mt:*Table) !*Table {
+    t.setMetaTable(mt);
+    return t;
+}
+
+test "method call" {
+    const p = try Program.init("tests/call.lout");
+    var vm = Vm.init(p);
+    defer vm.deinit();
+    vm.globals.setNoValidate(Var.from(Str.init("setmetatable")), Var.from(Func.wrap(setMetaTable)));
+    try vm.execUntilHaltDebug();
+
+    // The receiver is `self`, the results land in the return slots and nothing is left above them.
+    try std.testing.expectEqual(10, vm.pop().as(i32));
+    try std.testing.expectEqual(6, vm.pop().as(i32));
+    const obj = vm.pop().as(*Table);
+    try std.testing.expectEqual(10, (try obj.get(Var.from(Str.init("x")))).?.as(i32));
+    try std.testing.expectEqual(.table, vm.pop().tag());
+    try std.testing.expectEqual(.func, vm.pop().tag());
+}
+
+test "globals" {
+    const p = try Program.init("tests/global.lout");
+    var vm = Vm.init(p);
+    defer vm.deinit();
+    try vm.execUntilHaltDebug();
+
+    try std.testing.expectEqual(110, (try vm.globals.get(Var.from(Str.init("x")))).?.as(i32));
+    try std.testing.expectEqual(110, (try vm.globals.get(Var.from(Str.init("z")))).?.as(i32));
+}
+
+test "multi return" {
+    const p = try Program.init("tests/multi_return.lout");
+    var vm = Vm.init(p);
+    defer vm.deinit();
+    try vm.execUntilHaltDebug();
+
+    const t = vm.pop().as(*Table);
+    try std.testing.expectEqual(6, (try t.get(Var.from(Str.init("x")))).?.as(i32));
+    try std.testing.expectEqual(Var.nil_val, vm.pop());
+    try std.testing.expectEqual(2, vm.pop().as(i32));
+    try std.testing.expectEqual(1, vm.pop().as(i32));
+    try std.testing.expectEqual(2, vm.pop().as(i32));
+    try std.testing.expectEqual(2, vm.pop().as(i32));
+    try std.testing.expectEqual(Var.nil_val, vm.pop());
+    try std.testing.expectEqual(3, vm.pop().as(i32));
+}
+
+test "varargs" {
+    const p = try Program.init("tests/varargs.lout");
+    var vm = Vm.init(p);
+    defer vm.deinit();
+    try vm.execUntilHaltDebug();
+
+    // `pick(0)`: `y` and both varargs are padded with nil, `count` gets none.
+    try std.testing.expectEqual(Var.nil_val, vm.pop());
+    try std.testing.expectEqual(Var.nil_val, vm.pop());
+    try std.testing.expectEqual(0, vm.pop().as(i32));
+    try std.testing.expectEqual(Var.nil_val, vm.pop());
+    // `pick(0, 1, 2, 3, 4)`: the extra args are collected and spread into `count`.
+    try std.testing.expectEqual(3, vm.pop().as(i32));
+    try std.testing.expectEqual(2, vm.pop().as(i32));
+    try std.testing.expectEqual(3, vm.pop().as(i32));
+    try std.testing.expectEqual(1, vm.pop().as(i32));
+    try std.testing.expectEqual(.func, vm.pop().tag());
+    try std.testing.expectEqual(.func, vm.pop().tag());
+    try std.testing.expectEqual(.func, vm.pop().tag());
+}
+
+test "shared upvalues" {
+    const p = try Program.init("tests/shared_upval.lout");
+    var vm = Vm.init(p);
+    defer vm.deinit();
+    try vm.execUntilHaltDebug();
+
+    // Each closure in `fs` keeps the `i` and `x` of its own iteration, the last one closed by `break`.
+    try std.testing.expectEqual(0+2+4, vm.pop().as(i32));
+    try std.testing.expectEqual(.table, vm.pop().tag());
+    // `inc` and `get` see the same `n`, after it was returned.
+    try std.testing.expectEqual(2, vm.pop().as(i32));
+    try std.testing.expectEqual(1, vm.pop().as(i32));
+    try std.testing.expectEqual(.func, vm.pop().tag());
+    try std.testing.expectEqual(.func, vm.pop().tag());
+    try std.testing.expectEqual(.func, vm.pop().tag());
+}
+
+test "nested upvalues" {
+    const p = try Program.init("tests/nested_upval.lout");
+    var vm = Vm.init(p);
+    defer vm.deinit();
+    try vm.execUntilHaltDebug();
+
+    try std.testing.expectEqual(5, vm.pop().as(i32));
+    try std.testing.expectEqual(3, vm.pop().as(i32));
+    try std.testing.expectEqual(.func, vm.pop().tag());
+    // The innermost closure writes `x` in the main chunk while it is still open.
+    try std.testing.expectEqual(5, vm.pop().as(i32));
+    try std.testing.expectEqual(.func, vm.pop().tag());
+}
+
+test "lout header" {
+    const bytes = try std.fs.cwd().readFileAlloc(Vm.gpa, "tests/lout_file.lout", std.math.maxInt(u32));
+    defer Vm.gpa.free(bytes);
+
+    const corrupt = struct {
+        fn load(original:[]const u8, at:usize, x:u8) !Program {
+            const copy = try Vm.gpa.dupe(u8, original);
+            defer Vm.gpa.free(copy);
+            copy[at] = x;
+            return Program.initBytes(copy);
+        }
+    };
+
+    try std.testing.expectError(error.badMagic, corrupt.load(bytes, 0, 'L'));
+    try std.testing.expectError(error.unsupportedVersion, corrupt.load(bytes, 4, 9));
+    try std.testing.expectError(error.wrongEndianness, corrupt.load(bytes, 6, bytes[6]^1));
+    try std.testing.expectError(error.checksumMismatch, corrupt.load(bytes, bytes.len-1, bytes[bytes.len-1]^1));
+    try std.testing.expectError(error.truncated, Program.initBytes(bytes[0..10]));
+}
+
 //test "layout" {
 //    var int:u32 = 0;
 //    const x:*ByteCode = @ptrCast(&int);
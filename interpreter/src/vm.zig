@@ -4,10 +4,13 @@ const ByteCode = @import("bytecode.zig").ByteCode;
 const Program = @import("bytecode.zig").Program;
 const Str = @import("str.zig").Str;
 const Func = @import("func.zig").Func;
+const Upval = @import("func.zig").Upval;
+const Table = @import("table.zig").Table;
 const CallStack = Func.CallStack;
 const ReturnCode = @import("err.zig").ReturnCode;
 const exec_fn = @import("exec.zig").exec;
 const Err = @import("err.zig").Err;
+const var_ret_count = @import("bytecode.zig").var_ret_count;
 
 pub const Vm = struct {
     const Self = @This();
@@ -21,7 +24,12 @@ pub const Vm = struct {
     full_stack_slice:[]Var,
     sp:[*]Var,
     bp:[*]Var,
-    upval_ctx:[*]Var,
+    upval_ctx:[*]*Upval,
+    /// The upvalues still pointing into the stack, the highest slot first.
+    open_upvals:?*Upval,
+    /// The arguments after the parameters of a variadic function.
+    var_args:[]const Var,
+    globals:*Table,
 
     program:Program,
 
@@ -44,6 +52,9 @@ pub const Vm = struct {
             .bp = stack.ptr,
             .sp = stack.ptr,
             .upval_ctx = undefined,
+            .open_upvals = null,
+            .var_args = &.{},
+            .globals = Table.init(0),
             .call_stack = CallStack.init(Vm.gpa)
         };
 
@@ -65,7 +76,7 @@ pub const Vm = struct {
     pub fn execDebug(self:*Self) !void {
         self.printLocals();
         const instr = self.program.next(ByteCode);
-        std.debug.print("executing:{} {} \n", .{self.program.ip-self.program.list.items.ptr,instr});
+        std.debug.print("executing:{x} {} \n", .{@intFromPtr(self.program.ip-1),instr});
         exec_fn(instr, self) catch |err| switch (err) {
             ReturnCode.halt => return err,
             else => {
@@ -112,6 +123,51 @@ pub const Vm = struct {
         return @ptrCast(self.sp - 1);
     }
 
+    /// The open upvalue of `slot`, so every closure capturing it shares it.
+    pub fn captureUpval(self:*Self,slot:*Var) *Upval {
+        var link = &self.open_upvals;
+        while (link.*) |upval| {
+            if (@intFromPtr(upval.ptr) == @intFromPtr(slot)) {
+                return upval;
+            }
+            if (@intFromPtr(upval.ptr) < @intFromPtr(slot)) {
+                break;
+            }
+            link = &upval.next;
+        }
+
+        const upval = gpa.create(Upval) catch unreachable;
+        upval.* = .{.ptr = slot, .closed = undefined, .next = link.*};
+        link.* = upval;
+        return upval;
+    }
+
+    /// Moves the values of the open upvalues at `slot` and above into the upvalues.
+    pub fn closeUpvals(self:*Self,slot:[*]Var) void {
+        while (self.open_upvals) |upval| {
+            if (@intFromPtr(upval.ptr) < @intFromPtr(slot)) {
+                break;
+            }
+            upval.closed = upval.ptr.*;
+            upval.ptr = &upval.closed;
+            self.open_upvals = upval.next;
+        }
+    }
+
+    /// Pads the `n` results on top with nil or drops the extra ones, so there are `ret_count` of them.
+    /// With `var_ret_count` all of them are kept and their count is pushed.
+    pub fn adjustResults(self:*Self,n:usize,ret_count:u16) void {
+        if (ret_count == var_ret_count) {
+            self.push(Var.from(@as(i32,@intCast(n))));
+        } else if (n > ret_count) {
+            self.sp -= n-ret_count;
+        } else {
+            for (n..ret_count) |_| {
+                self.push(Var.nil_val);
+            }
+        }
+    }
+
     pub fn localSlice(self:*Self) []Var {
         const size = self.sp-self.bp;
         return self.bp[0..size];